@@ -1,3 +1,7 @@
 fn main() {
-    prost_build::compile_protos(&["src/proto/database.proto"], &["src/"]).unwrap();
+    prost_build::compile_protos(
+        &["src/proto/database.proto", "src/proto/sync.proto"],
+        &["src/"],
+    )
+    .unwrap();
 }
@@ -0,0 +1,48 @@
+use clap::{App, Arg};
+use ring::digest::{digest, SHA256};
+
+use keyserver::token::HmacTokenScheme;
+
+fn main() {
+    let matches = App::new("Cash:web Keyserver token issuer")
+        .about("Issues POP tokens for keyservers using the HMAC token scheme")
+        .version(clap::crate_version!())
+        .arg(
+            Arg::with_name("secret")
+                .long("secret")
+                .env("KEYSERVER_HMAC_SECRET")
+                .help("Hex encoded secret key, matching tokens.hmac_secret")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("public-key")
+                .long("public-key")
+                .help("Hex encoded public key the token is issued to")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("metadata-digest")
+                .long("metadata-digest")
+                .help("Hex encoded SHA256 digest of the metadata payload")
+                .takes_value(true)
+                .required(true),
+        )
+        .get_matches();
+
+    let decode = |name: &str| {
+        hex::decode(matches.value_of(name).unwrap()).unwrap_or_else(|err| {
+            eprintln!("{} must be hex encoded: {}", name, err);
+            std::process::exit(1)
+        })
+    };
+    let secret = decode("secret");
+    let public_key = decode("public-key");
+    let metadata_digest = decode("metadata-digest");
+
+    let pub_key_hash = digest(&SHA256, &public_key);
+    let token =
+        HmacTokenScheme::new(&secret).construct_token(pub_key_hash.as_ref(), &metadata_digest);
+    println!("POP {}", token);
+}
@@ -1,12 +1,24 @@
 use std::sync::Arc;
 
 use prost::Message as _;
-use rocksdb::{Error as RocksError, Options, DB};
+use rocksdb::{Error as RocksError, Options, WriteBatch, DB};
 
-use crate::models::{database::DatabaseWrapper, keyserver::Peers};
+use crate::models::{
+    database::{
+        BroadcastRecord, DatabaseWrapper, ExclusionRecord, PaymentRecord, PendingRecord,
+        WatchRecord,
+    },
+    keyserver::Peers,
+};
 
 const METADATA_NAMESPACE: u8 = b'm';
 const PEER_NAMESPACE: u8 = b'p';
+const PAYMENT_NAMESPACE: u8 = b'y';
+const WATCH_NAMESPACE: u8 = b'w';
+const OUTPOINT_NAMESPACE: u8 = b'o';
+const BROADCAST_NAMESPACE: u8 = b'b';
+const EXCLUSION_NAMESPACE: u8 = b'x';
+const PENDING_NAMESPACE: u8 = b'q';
 
 #[derive(Clone)]
 pub struct Database(Arc<DB>);
@@ -16,7 +28,7 @@ impl Database {
         let mut opts = Options::default();
         opts.create_if_missing(true);
 
-        DB::open(&opts, &path).map(Arc::new).map(Database)
+        DB::open(&opts, path).map(Arc::new).map(Database)
     }
 
     /// Get raw `DatabaseWrapper` from the database.
@@ -42,6 +54,19 @@ impl Database {
         self.0.put(key, raw)
     }
 
+    /// Get the `DatabaseWrapper`s whose address starts with the given byte, in address order.
+    pub fn get_metadata_bucket(&self, bucket: u8) -> Vec<(Vec<u8>, DatabaseWrapper)> {
+        let prefix = [METADATA_NAMESPACE, bucket];
+        self.0
+            .prefix_iterator(prefix)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, raw)| {
+                let database_wrapper = DatabaseWrapper::decode(&raw[..]).unwrap(); // This panics if stored bytes are malformed
+                (key[1..].to_vec(), database_wrapper)
+            })
+            .collect()
+    }
+
     /// Get `Peers` from database.
     pub fn get_peers(&self) -> Result<Option<Peers>, RocksError> {
         self.get_peers_raw().map(|raw_peers_opt| {
@@ -60,6 +85,214 @@ impl Database {
     pub fn put_peers(&self, raw: &[u8]) -> Result<(), RocksError> {
         self.0.put([PEER_NAMESPACE], raw)
     }
+
+    /// Get a `PaymentRecord` from the database.
+    pub fn get_payment(&self, tx_id: &[u8]) -> Result<Option<PaymentRecord>, RocksError> {
+        let key = [&[PAYMENT_NAMESPACE], tx_id].concat();
+        self.0.get(key).map(|raw_opt| {
+            raw_opt.map(|raw| {
+                PaymentRecord::decode(&raw[..]).unwrap() // This panics if stored bytes are malformed
+            })
+        })
+    }
+
+    /// Put a serialized `PaymentRecord` to the database.
+    pub fn put_payment(&self, tx_id: &[u8], raw: &[u8]) -> Result<(), RocksError> {
+        let key = [&[PAYMENT_NAMESPACE], tx_id].concat();
+        self.0.put(key, raw)
+    }
+
+    /// Mark a `DatabaseWrapper` invalid, if its token commits to the given transaction.
+    ///
+    /// Returns whether the metadata was invalidated.
+    pub fn invalidate_metadata(&self, addr: &[u8], tx_id: &[u8]) -> Result<bool, RocksError> {
+        let mut database_wrapper = match self.get_metadata(addr)? {
+            Some(some) => some,
+            None => return Ok(false),
+        };
+        if database_wrapper.invalid || !database_wrapper.token.starts_with(tx_id) {
+            return Ok(false);
+        }
+        database_wrapper.invalid = true;
+
+        let mut raw = Vec::with_capacity(database_wrapper.encoded_len());
+        database_wrapper.encode(&mut raw).unwrap(); // This is safe
+        self.put_metadata(addr, &raw)?;
+        Ok(true)
+    }
+
+    /// Get a `WatchRecord` from the database.
+    pub fn get_watch(&self, tx_id: &[u8]) -> Result<Option<WatchRecord>, RocksError> {
+        let key = [&[WATCH_NAMESPACE], tx_id].concat();
+        self.0.get(key).map(|raw_opt| {
+            raw_opt.map(|raw| {
+                WatchRecord::decode(&raw[..]).unwrap() // This panics if stored bytes are malformed
+            })
+        })
+    }
+
+    /// Get all `WatchRecord`s from the database, along with their transaction IDs.
+    pub fn get_watches(&self) -> Vec<(Vec<u8>, WatchRecord)> {
+        self.0
+            .prefix_iterator([WATCH_NAMESPACE])
+            .take_while(|(key, _)| key.first() == Some(&WATCH_NAMESPACE))
+            .map(|(key, raw)| {
+                let watch_record = WatchRecord::decode(&raw[..]).unwrap(); // This panics if stored bytes are malformed
+                (key[1..].to_vec(), watch_record)
+            })
+            .collect()
+    }
+
+    /// Put a `WatchRecord` to the database, indexing the outpoints it spends.
+    pub fn put_watch(&self, tx_id: &[u8], watch_record: &WatchRecord) -> Result<(), RocksError> {
+        let mut batch = WriteBatch::default();
+        for outpoint in &watch_record.outpoints {
+            batch.put([&[OUTPOINT_NAMESPACE], &outpoint[..]].concat(), tx_id);
+        }
+        let mut raw = Vec::with_capacity(watch_record.encoded_len());
+        watch_record.encode(&mut raw).unwrap(); // This is safe
+        batch.put([&[WATCH_NAMESPACE], tx_id].concat(), raw);
+        self.0.write(batch)
+    }
+
+    /// Remove a `WatchRecord` from the database, along with its outpoint index.
+    pub fn delete_watch(&self, tx_id: &[u8]) -> Result<(), RocksError> {
+        let mut batch = WriteBatch::default();
+        if let Some(watch_record) = self.get_watch(tx_id)? {
+            for outpoint in &watch_record.outpoints {
+                batch.delete([&[OUTPOINT_NAMESPACE], &outpoint[..]].concat());
+            }
+        }
+        batch.delete([&[WATCH_NAMESPACE], tx_id].concat());
+        self.0.write(batch)
+    }
+
+    /// Get the ID of the watched transaction spending an outpoint.
+    pub fn get_outpoint_spender(&self, outpoint: &[u8]) -> Result<Option<Vec<u8>>, RocksError> {
+        let key = [&[OUTPOINT_NAMESPACE], outpoint].concat();
+        self.0.get(key)
+    }
+
+    /// Get all `BroadcastRecord`s from the database, along with their addresses.
+    pub fn get_broadcasts(&self) -> Vec<(Vec<u8>, BroadcastRecord)> {
+        self.0
+            .prefix_iterator([BROADCAST_NAMESPACE])
+            .take_while(|(key, _)| key.first() == Some(&BROADCAST_NAMESPACE))
+            .map(|(key, raw)| {
+                let broadcast_record = BroadcastRecord::decode(&raw[..]).unwrap(); // This panics if stored bytes are malformed
+                (key[1..].to_vec(), broadcast_record)
+            })
+            .collect()
+    }
+
+    /// Get a `BroadcastRecord` from the database.
+    pub fn get_broadcast(&self, addr: &[u8]) -> Result<Option<BroadcastRecord>, RocksError> {
+        let key = [&[BROADCAST_NAMESPACE], addr].concat();
+        self.0.get(key).map(|raw_opt| {
+            raw_opt.map(|raw| {
+                BroadcastRecord::decode(&raw[..]).unwrap() // This panics if stored bytes are malformed
+            })
+        })
+    }
+
+    /// Put a `BroadcastRecord` to the database.
+    pub fn put_broadcast(
+        &self,
+        addr: &[u8],
+        broadcast_record: &BroadcastRecord,
+    ) -> Result<(), RocksError> {
+        let key = [&[BROADCAST_NAMESPACE], addr].concat();
+        let mut raw = Vec::with_capacity(broadcast_record.encoded_len());
+        broadcast_record.encode(&mut raw).unwrap(); // This is safe
+        self.0.put(key, raw)
+    }
+
+    /// Remove a `BroadcastRecord` from the database.
+    pub fn delete_broadcast(&self, addr: &[u8]) -> Result<(), RocksError> {
+        let key = [&[BROADCAST_NAMESPACE], addr].concat();
+        self.0.delete(key)
+    }
+
+    /// Get all `ExclusionRecord`s from the database, along with their peer URLs.
+    pub fn get_exclusions(&self) -> Vec<(String, ExclusionRecord)> {
+        self.0
+            .prefix_iterator([EXCLUSION_NAMESPACE])
+            .take_while(|(key, _)| key.first() == Some(&EXCLUSION_NAMESPACE))
+            .map(|(key, raw)| {
+                let exclusion_record = ExclusionRecord::decode(&raw[..]).unwrap(); // This panics if stored bytes are malformed
+                (
+                    String::from_utf8_lossy(&key[1..]).into_owned(),
+                    exclusion_record,
+                )
+            })
+            .collect()
+    }
+
+    /// Put an `ExclusionRecord` to the database.
+    pub fn put_exclusion(
+        &self,
+        url: &str,
+        exclusion_record: &ExclusionRecord,
+    ) -> Result<(), RocksError> {
+        let key = [&[EXCLUSION_NAMESPACE], url.as_bytes()].concat();
+        let mut raw = Vec::with_capacity(exclusion_record.encoded_len());
+        exclusion_record.encode(&mut raw).unwrap(); // This is safe
+        self.0.put(key, raw)
+    }
+
+    /// Remove an `ExclusionRecord` from the database.
+    pub fn delete_exclusion(&self, url: &str) -> Result<(), RocksError> {
+        let key = [&[EXCLUSION_NAMESPACE], url.as_bytes()].concat();
+        self.0.delete(key)
+    }
+
+    /// Get all `PendingRecord`s from the database, along with their addresses.
+    pub fn get_pendings(&self) -> Vec<(Vec<u8>, PendingRecord)> {
+        self.0
+            .prefix_iterator([PENDING_NAMESPACE])
+            .take_while(|(key, _)| key.first() == Some(&PENDING_NAMESPACE))
+            .map(|(key, raw)| {
+                let pending_record = PendingRecord::decode(&raw[..]).unwrap(); // This panics if stored bytes are malformed
+                (key[1..].to_vec(), pending_record)
+            })
+            .collect()
+    }
+
+    /// Count the `PendingRecord`s in the database.
+    pub fn count_pendings(&self) -> usize {
+        self.0
+            .prefix_iterator([PENDING_NAMESPACE])
+            .take_while(|(key, _)| key.first() == Some(&PENDING_NAMESPACE))
+            .count()
+    }
+
+    /// Get a `PendingRecord` from the database.
+    pub fn get_pending(&self, addr: &[u8]) -> Result<Option<PendingRecord>, RocksError> {
+        let key = [&[PENDING_NAMESPACE], addr].concat();
+        self.0.get(key).map(|raw_opt| {
+            raw_opt.map(|raw| {
+                PendingRecord::decode(&raw[..]).unwrap() // This panics if stored bytes are malformed
+            })
+        })
+    }
+
+    /// Put a `PendingRecord` to the database.
+    pub fn put_pending(
+        &self,
+        addr: &[u8],
+        pending_record: &PendingRecord,
+    ) -> Result<(), RocksError> {
+        let key = [&[PENDING_NAMESPACE], addr].concat();
+        let mut raw = Vec::with_capacity(pending_record.encoded_len());
+        pending_record.encode(&mut raw).unwrap(); // This is safe
+        self.0.put(key, raw)
+    }
+
+    /// Remove a `PendingRecord` from the database.
+    pub fn delete_pending(&self, addr: &[u8]) -> Result<(), RocksError> {
+        let key = [&[PENDING_NAMESPACE], addr].concat();
+        self.0.delete(key)
+    }
 }
 
 #[cfg(test)]
@@ -68,7 +301,7 @@ pub mod tests {
 
     use super::*;
     use crate::models::{
-        database::DatabaseWrapper,
+        database::{BroadcastRecord, DatabaseWrapper, ExclusionRecord, PaymentRecord, WatchRecord},
         keyserver::{Peer, Peers},
     };
 
@@ -115,6 +348,8 @@ pub mod tests {
         let database_wrapper_in = DatabaseWrapper {
             token: vec![0, 1, 3, 4],
             serialized_auth_wrapper: vec![2, 3, 4],
+            invalid: false,
+            operator_published: false,
         };
         let mut database_wrapper_raw = Vec::with_capacity(database_wrapper_in.encoded_len());
         database_wrapper_in
@@ -129,6 +364,175 @@ pub mod tests {
         let data_wrapper_out = database.get_metadata(&addr).unwrap().unwrap();
         assert_eq!(database_wrapper_in, data_wrapper_out);
 
+        // Invalidate
+        assert!(!database.invalidate_metadata(&addr, &[5, 6]).unwrap());
+        assert!(database.invalidate_metadata(&addr, &[0, 1]).unwrap());
+        assert!(database.get_metadata(&addr).unwrap().unwrap().invalid);
+
+        // Destroy database
+        drop(database);
+        DB::destroy(&Options::default(), TEST_NAME).unwrap();
+    }
+
+    #[test]
+    fn payments() {
+        const TEST_NAME: &str = "./tests/payments";
+
+        // Create database
+        let database = Database::try_new(TEST_NAME).unwrap();
+
+        // Create payment record
+        let payment_record_in = PaymentRecord {
+            serialized_payment_ack: vec![1, 2, 3],
+            token: "POP abcd".to_string(),
+            address: "address".to_string(),
+            size: 200,
+        };
+        let mut payment_record_raw = Vec::with_capacity(payment_record_in.encoded_len());
+        payment_record_in.encode(&mut payment_record_raw).unwrap();
+
+        // Put to database
+        let tx_id = vec![7; 32];
+        database.put_payment(&tx_id, &payment_record_raw).unwrap();
+
+        // Get from database
+        let payment_record_out = database.get_payment(&tx_id).unwrap().unwrap();
+        assert_eq!(payment_record_in, payment_record_out);
+        assert!(database.get_payment(&[8; 32]).unwrap().is_none());
+
+        // Destroy database
+        drop(database);
+        DB::destroy(&Options::default(), TEST_NAME).unwrap();
+    }
+
+    #[test]
+    fn watches() {
+        const TEST_NAME: &str = "./tests/watches";
+
+        // Create database
+        let database = Database::try_new(TEST_NAME).unwrap();
+
+        // Put to database
+        let tx_id = vec![7; 32];
+        let outpoint = vec![3; 36];
+        let watch_record_in = WatchRecord {
+            address: vec![1, 2, 3],
+            outpoints: vec![outpoint.clone()],
+        };
+        database.put_watch(&tx_id, &watch_record_in).unwrap();
+
+        // Get from database
+        let watch_record_out = database.get_watch(&tx_id).unwrap().unwrap();
+        assert_eq!(watch_record_in, watch_record_out);
+        assert_eq!(
+            database.get_watches(),
+            vec![(tx_id.clone(), watch_record_in)]
+        );
+        assert_eq!(
+            database.get_outpoint_spender(&outpoint).unwrap(),
+            Some(tx_id.clone())
+        );
+
+        // Delete from database
+        database.delete_watch(&tx_id).unwrap();
+        assert!(database.get_watch(&tx_id).unwrap().is_none());
+        assert!(database.get_outpoint_spender(&outpoint).unwrap().is_none());
+
+        // Destroy database
+        drop(database);
+        DB::destroy(&Options::default(), TEST_NAME).unwrap();
+    }
+
+    #[test]
+    fn broadcasts() {
+        const TEST_NAME: &str = "./tests/broadcasts";
+
+        // Create database
+        let database = Database::try_new(TEST_NAME).unwrap();
+
+        // Put to database
+        let addr = vec![4; 20];
+        let broadcast_record_in = BroadcastRecord {
+            target_height: 102,
+            peers: vec!["url a".to_string()],
+            attempts: 1,
+        };
+        database.put_broadcast(&addr, &broadcast_record_in).unwrap();
+
+        // Get from database
+        let broadcast_record_out = database.get_broadcast(&addr).unwrap().unwrap();
+        assert_eq!(broadcast_record_in, broadcast_record_out);
+        assert_eq!(
+            database.get_broadcasts(),
+            vec![(addr.clone(), broadcast_record_in)]
+        );
+
+        // Delete from database
+        database.delete_broadcast(&addr).unwrap();
+        assert!(database.get_broadcast(&addr).unwrap().is_none());
+        assert!(database.get_broadcasts().is_empty());
+
+        // Destroy database
+        drop(database);
+        DB::destroy(&Options::default(), TEST_NAME).unwrap();
+    }
+
+    #[test]
+    fn exclusions() {
+        const TEST_NAME: &str = "./tests/exclusions";
+
+        // Create database
+        let database = Database::try_new(TEST_NAME).unwrap();
+
+        // Put to database
+        let url = "http://peer.example/";
+        let exclusion_record_in = ExclusionRecord { banned_until: 0 };
+        database.put_exclusion(url, &exclusion_record_in).unwrap();
+
+        // Get from database
+        assert_eq!(
+            database.get_exclusions(),
+            vec![(url.to_string(), exclusion_record_in)]
+        );
+
+        // Delete from database
+        database.delete_exclusion(url).unwrap();
+        assert!(database.get_exclusions().is_empty());
+
+        // Destroy database
+        drop(database);
+        DB::destroy(&Options::default(), TEST_NAME).unwrap();
+    }
+
+    #[test]
+    fn pendings() {
+        const TEST_NAME: &str = "./tests/pendings";
+
+        // Create database
+        let database = Database::try_new(TEST_NAME).unwrap();
+
+        // Put to database
+        let addr = vec![5; 20];
+        let pending_record_in = PendingRecord {
+            serialized_auth_wrapper: vec![1, 2, 3],
+            token: vec![6; 36],
+        };
+        database.put_pending(&addr, &pending_record_in).unwrap();
+
+        // Get from database
+        let pending_record_out = database.get_pending(&addr).unwrap().unwrap();
+        assert_eq!(pending_record_in, pending_record_out);
+        assert_eq!(database.count_pendings(), 1);
+        assert_eq!(
+            database.get_pendings(),
+            vec![(addr.clone(), pending_record_in)]
+        );
+
+        // Delete from database
+        database.delete_pending(&addr).unwrap();
+        assert!(database.get_pending(&addr).unwrap().is_none());
+        assert_eq!(database.count_pendings(), 0);
+
         // Destroy database
         drop(database);
         DB::destroy(&Options::default(), TEST_NAME).unwrap();
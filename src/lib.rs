@@ -0,0 +1,402 @@
+#[macro_use]
+extern crate clap;
+extern crate serde;
+
+pub mod db;
+pub mod models;
+pub mod net;
+pub mod node;
+pub mod peering;
+pub mod settings;
+pub mod token;
+pub mod watcher;
+
+#[cfg(feature = "monitoring")]
+pub mod monitoring;
+
+use std::sync::Arc;
+
+use futures::prelude::*;
+use lazy_static::lazy_static;
+use warp::{
+    http::{header, Method},
+    Filter, Rejection, Reply,
+};
+
+use db::Database;
+use net::{protection, PendingQueue, RateLimiter};
+use node::{BlockNotifier, Node};
+use peering::{DigestCache, PeerClient, PeerHandler, TokenCache};
+use settings::{Settings, TokenSchemeKind};
+use token::{ChainCommitmentScheme, HmacTokenScheme, TokenScheme, ValidationCache};
+
+pub const METADATA_PATH: &str = "keys";
+pub const PEERS_PATH: &str = "peers";
+pub const INFO_PATH: &str = "info";
+pub const PAYMENTS_PATH: &str = "payments";
+pub const INVOICES_PATH: &str = "invoices";
+pub const QR_PATH: &str = "qr.png";
+pub const TOKENS_PATH: &str = "tokens";
+pub const VALIDATE_PATH: &str = "validate";
+pub const SYNC_PATH: &str = "sync";
+pub const DIGESTS_PATH: &str = "digests";
+pub const BUCKETS_PATH: &str = "buckets";
+pub const ADMIN_PATH: &str = "admin";
+pub const BAN_PATH: &str = "ban";
+pub const CRAWL_PATH: &str = "crawl";
+pub const INTERNAL_PATH: &str = "internal";
+pub const BLOCK_NOTIFY_PATH: &str = "blocknotify";
+
+lazy_static! {
+    // Static settings
+    pub static ref SETTINGS: Settings = Settings::new().expect("couldn't load config");
+}
+
+/// Construct the configured token scheme.
+pub fn token_scheme<N: Node>(node: N, validation_cache: ValidationCache) -> Arc<dyn TokenScheme> {
+    match SETTINGS.tokens.scheme {
+        TokenSchemeKind::ChainCommitment => {
+            Arc::new(ChainCommitmentScheme::new(node).with_cache(validation_cache))
+        }
+        TokenSchemeKind::Hmac => {
+            let secret = SETTINGS
+                .tokens
+                .hmac_secret
+                .as_ref()
+                .expect("hmac token scheme requires tokens.hmac_secret");
+            let secret = hex::decode(secret).expect("tokens.hmac_secret must be hex encoded");
+            Arc::new(HmacTokenScheme::new(&secret))
+        }
+    }
+}
+
+/// Construct the REST API.
+#[allow(clippy::too_many_arguments)]
+pub fn rest_api<N: Node>(
+    db: Database,
+    node: N,
+    peer_handler: PeerHandler<PeerClient>,
+    token_cache: TokenCache,
+    digest_cache: DigestCache,
+    pending_queue: PendingQueue,
+    validation_cache: ValidationCache,
+    block_notifier: BlockNotifier,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    // Peer state
+    let peer_handler = warp::any().map(move || peer_handler.clone());
+
+    // Database state
+    let db_state = warp::any().map(move || db.clone());
+
+    // Address string converter
+    let addr_base = warp::path::param().and_then(|addr_str: String| async move {
+        net::address_decode(&addr_str).map_err(warp::reject::custom)
+    });
+
+    // Token scheme
+    let token_scheme = token_scheme(node.clone(), validation_cache);
+    let token_scheme_state = warp::any().map(move || token_scheme.clone());
+
+    // Token cache state
+    let token_cache_state = warp::any().map(move || token_cache.clone());
+
+    // Pending metadata state
+    let pending_queue_state = warp::any().map(move || pending_queue.clone());
+
+    // Bitcoin node state
+    let node_state = warp::any().map(move || node.clone());
+
+    // Rate limits
+    let rate_limits = &SETTINGS.rate_limits;
+    let keys_get_limit = net::rate_limit(RateLimiter::new(rate_limits.keys_get.as_ref()));
+    let keys_put_limit = net::rate_limit(RateLimiter::new(rate_limits.keys_put.as_ref()));
+    let payments_limit = net::rate_limit(RateLimiter::new(rate_limits.payments.as_ref()));
+    let peers_limit = net::rate_limit(RateLimiter::new(rate_limits.peers.as_ref()));
+
+    // Peer authentication
+    let accepted_fingerprints = net::accepted_fingerprints().expect("invalid peering.tls settings");
+    let peer_auth = net::peer_auth(Arc::new(accepted_fingerprints));
+
+    // Protection
+    let addr_protected = addr_base
+        .and(warp::body::content_length_limit(
+            SETTINGS.limits.metadata_size,
+        ))
+        .and(warp::body::bytes())
+        .and(warp::header::headers_cloned())
+        .and(token_scheme_state.clone())
+        .and(node_state.clone())
+        .and(pending_queue_state)
+        .and_then(
+            move |addr, body, headers, token_scheme, node, pending_queue| {
+                protection::pop_protection(addr, body, headers, token_scheme, node, pending_queue)
+                    .map_err(warp::reject::custom)
+            },
+        )
+        .untuple_one();
+
+    // Metadata handlers
+    let metadata_get = warp::path(METADATA_PATH)
+        .and(addr_base)
+        .and(warp::get())
+        .and(keys_get_limit)
+        .and(warp::header::headers_cloned())
+        .and(db_state.clone())
+        .and(peer_handler.clone())
+        .and_then(move |addr, headers, db, peer_handler| {
+            net::get_metadata(addr, headers, db, peer_handler).map_err(warp::reject::custom)
+        });
+    let metadata_put = warp::path(METADATA_PATH)
+        .and(warp::put())
+        .and(keys_put_limit.clone())
+        .and(addr_protected)
+        .and(warp::body::content_length_limit(
+            SETTINGS.limits.metadata_size,
+        ))
+        .and(db_state.clone())
+        .and(token_cache_state)
+        .and_then(
+            move |addr, auth_wrapper_raw, auth_wrapper, authorization, db, token_cache| {
+                net::put_metadata(
+                    addr,
+                    auth_wrapper_raw,
+                    auth_wrapper,
+                    authorization,
+                    db,
+                    token_cache,
+                )
+                .map_err(warp::reject::custom)
+            },
+        );
+
+    // Peer handler
+    let peers_get = warp::path(PEERS_PATH)
+        .and(warp::get())
+        .and(peers_limit.clone())
+        .and(peer_auth.clone())
+        .and(peer_handler.clone())
+        .and_then(move |peer_handler| net::get_peers(peer_handler).map_err(warp::reject::custom));
+    let peers_post = warp::path(PEERS_PATH)
+        .and(warp::post())
+        .and(peers_limit.clone())
+        .and(peer_auth.clone())
+        .and(warp::body::content_length_limit(net::ANNOUNCEMENT_LIMIT))
+        .and(warp::body::bytes())
+        .and(net::client_addr())
+        .and(peer_handler.clone())
+        .and_then(move |body, source, peer_handler| {
+            net::post_peer(body, source, peer_handler).map_err(warp::reject::custom)
+        });
+
+    // Info handler
+    let info_get = warp::path(INFO_PATH)
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(peers_limit.clone())
+        .and(peer_auth.clone())
+        .map(net::get_info);
+
+    // Admin handlers
+    let admin_auth = warp::header::optional::<String>(net::API_KEY)
+        .and_then(|key| net::authorize_admin(key).map_err(warp::reject::custom))
+        .untuple_one();
+    let admin_peers_get = warp::path(ADMIN_PATH)
+        .and(warp::path(PEERS_PATH))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(admin_auth)
+        .and(peer_handler.clone())
+        .and_then(move |peer_handler| {
+            net::get_admin_peers(peer_handler).map_err(warp::reject::custom)
+        });
+    let admin_peers_post = warp::path(ADMIN_PATH)
+        .and(warp::path(PEERS_PATH))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(admin_auth)
+        .and(warp::body::content_length_limit(net::ADMIN_REQUEST_LIMIT))
+        .and(warp::body::bytes())
+        .and(peer_handler.clone())
+        .and(db_state.clone())
+        .and_then(move |body, peer_handler, db| {
+            net::post_admin_peer(body, peer_handler, db).map_err(warp::reject::custom)
+        });
+    let admin_peers_delete = warp::path(ADMIN_PATH)
+        .and(warp::path(PEERS_PATH))
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(admin_auth)
+        .and(warp::query())
+        .and(peer_handler.clone())
+        .and(db_state.clone())
+        .and_then(move |request, peer_handler, db| {
+            net::delete_admin_peer(request, peer_handler, db).map_err(warp::reject::custom)
+        });
+    let admin_peers_ban = warp::path(ADMIN_PATH)
+        .and(warp::path(PEERS_PATH))
+        .and(warp::path(BAN_PATH))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(admin_auth)
+        .and(warp::body::content_length_limit(net::ADMIN_REQUEST_LIMIT))
+        .and(warp::body::bytes())
+        .and(peer_handler.clone())
+        .and(db_state.clone())
+        .and_then(move |body, peer_handler, db| {
+            net::post_admin_ban(body, peer_handler, db).map_err(warp::reject::custom)
+        });
+    let admin_peers_crawl = warp::path(ADMIN_PATH)
+        .and(warp::path(PEERS_PATH))
+        .and(warp::path(CRAWL_PATH))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(admin_auth)
+        .and(peer_handler)
+        .and(db_state.clone())
+        .and_then(move |peer_handler, db| {
+            net::post_admin_crawl(peer_handler, db).map_err(warp::reject::custom)
+        });
+
+    // Synchronization handlers
+    let sync_digests = warp::path(SYNC_PATH)
+        .and(warp::path(DIGESTS_PATH))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(peers_limit.clone())
+        .and(peer_auth.clone())
+        .and(warp::any().map(move || digest_cache.clone()))
+        .and_then(move |digest_cache| {
+            net::get_sync_digests(digest_cache).map_err(warp::reject::custom)
+        });
+    let sync_bucket = warp::path(SYNC_PATH)
+        .and(warp::path(BUCKETS_PATH))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(peers_limit)
+        .and(peer_auth)
+        .and(db_state.clone())
+        .and_then(move |bucket, db| net::get_sync_bucket(bucket, db).map_err(warp::reject::custom));
+
+    // Payment handler
+    let payments = warp::path(PAYMENTS_PATH)
+        .and(warp::post())
+        .and(payments_limit.clone())
+        .and(warp::header::headers_cloned())
+        .and(warp::query())
+        .and(warp::body::content_length_limit(
+            SETTINGS.limits.payment_size,
+        ))
+        .and(warp::body::bytes())
+        .and_then(move |headers, query, body| {
+            net::parse_payment(headers, query, body).map_err(warp::reject::custom)
+        })
+        .untuple_one()
+        .and(node_state.clone())
+        .and(db_state.clone())
+        .and_then(move |payment, format, node, db| async move {
+            net::process_payment(payment, format, node, db)
+                .await
+                .map_err(warp::reject::custom)
+        });
+    let payments_get = warp::path(PAYMENTS_PATH)
+        .and(warp::path::param())
+        .and(warp::get())
+        .and(payments_limit.clone())
+        .and(warp::header::headers_cloned())
+        .and(node_state.clone())
+        .and(db_state.clone())
+        .and_then(move |tx_id, headers, node, db| {
+            net::get_payment(tx_id, headers, node, db).map_err(warp::reject::custom)
+        });
+
+    // Token introspection handler
+    let tokens_validate = warp::path(TOKENS_PATH)
+        .and(warp::path(VALIDATE_PATH))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(keys_put_limit)
+        .and(warp::body::content_length_limit(net::VALIDATE_TOKEN_LIMIT))
+        .and(warp::body::bytes())
+        .and(token_scheme_state)
+        .and(db_state)
+        .and_then(move |body, token_scheme, db| {
+            net::validate_token(body, token_scheme, db).map_err(warp::reject::custom)
+        });
+
+    // Invoice handlers
+    let invoices_get = warp::path(INVOICES_PATH)
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(payments_limit.clone())
+        .and(warp::header::headers_cloned())
+        .and_then(move |invoice_id, headers| {
+            net::get_invoice(invoice_id, headers).map_err(warp::reject::custom)
+        });
+    let invoices_qr_get = warp::path(INVOICES_PATH)
+        .and(warp::path::param())
+        .and(warp::path(QR_PATH))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(payments_limit)
+        .and_then(move |invoice_id| net::get_invoice_qr(invoice_id).map_err(warp::reject::custom));
+
+    // Block notification handler
+    let block_notify = warp::path(INTERNAL_PATH)
+        .and(warp::path(BLOCK_NOTIFY_PATH))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(net::BLOCK_NOTIFY_LIMIT))
+        .and(warp::body::bytes())
+        .and(net::client_addr())
+        .and(warp::header::optional::<String>(net::NOTIFY_SECRET))
+        .and(warp::any().map(move || block_notifier.clone()))
+        .and_then(move |body, client, secret, block_notifier| {
+            net::post_block_notify(body, client, secret, block_notifier)
+                .map_err(warp::reject::custom)
+        });
+
+    // Root handler
+    let root = warp::path::end()
+        .and(warp::get())
+        .and(warp::fs::file("./static/index.html"));
+
+    // CORs
+    let cors = warp::cors()
+        .allow_any_origin()
+        .allow_methods(vec![Method::GET, Method::PUT, Method::POST, Method::DELETE])
+        .allow_headers(vec![header::AUTHORIZATION, header::CONTENT_TYPE])
+        .allow_header(net::API_KEY)
+        .expose_headers(vec![
+            header::AUTHORIZATION,
+            header::ACCEPT,
+            header::LOCATION,
+        ])
+        .expose_header(net::PAYMENT_URI)
+        .expose_header(protection::CONFIRMATIONS_REMAINING)
+        .expose_header(net::OPERATOR_PUBLISHED)
+        .build();
+
+    root.or(payments)
+        .or(payments_get)
+        .or(invoices_get)
+        .or(invoices_qr_get)
+        .or(metadata_get)
+        .or(metadata_put)
+        .or(peers_get)
+        .or(peers_post)
+        .or(info_get)
+        .or(sync_digests)
+        .or(sync_bucket)
+        .or(tokens_validate)
+        .or(block_notify)
+        .or(admin_peers_get)
+        .or(admin_peers_post)
+        .or(admin_peers_delete)
+        .or(admin_peers_ban)
+        .or(admin_peers_crawl)
+        .recover(net::handle_rejection)
+        .with(cors)
+        .with(warp::trace::request())
+}
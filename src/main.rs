@@ -1,45 +1,27 @@
-#[macro_use]
-extern crate clap;
-extern crate serde;
+use std::{env, time::Duration};
 
-pub mod db;
-pub mod models;
-pub mod net;
-pub mod peering;
-pub mod settings;
-
-#[cfg(feature = "monitoring")]
-pub mod monitoring;
-
-use std::{env, sync::Arc, time::Duration};
-
-use cashweb::{
-    bitcoin_client::BitcoinClient, payments::preprocess_payment,
-    token::schemes::chain_commitment::ChainCommitmentScheme,
-};
+use cashweb::bitcoin_client::BitcoinClient;
 use futures::prelude::*;
 use hyper::{client::HttpConnector, http::Uri};
-use lazy_static::lazy_static;
-use tracing::{error, info};
+use tokio::time;
+use tracing::{error, info, warn};
 use tracing_subscriber::{fmt, EnvFilter};
-use warp::{
-    http::{header, Method},
-    Filter,
-};
-
-use db::Database;
-use net::{payments, protection};
-use peering::{PeerHandler, TokenCache};
-use settings::Settings;
-
-const METADATA_PATH: &str = "keys";
-const PEERS_PATH: &str = "peers";
-pub const PAYMENTS_PATH: &str = "payments";
 
-lazy_static! {
-    // Static settings
-    pub static ref SETTINGS: Settings = Settings::new().expect("couldn't load config");
-}
+#[cfg(feature = "monitoring")]
+use keyserver::monitoring;
+#[cfg(feature = "monitoring")]
+use warp::Filter;
+
+use keyserver::{
+    db::Database,
+    net::{self, PendingQueue},
+    node::{BitcoindNode, BlockNotifier, BlockSource, Node},
+    peering::{self, DigestCache, PeerHandler, TokenCache},
+    settings::BlockSourceKind,
+    token::ValidationCache,
+    watcher::TokenWatcher,
+    SETTINGS,
+};
 
 #[tokio::main]
 async fn main() {
@@ -51,6 +33,11 @@ async fn main() {
         .finish();
     tracing::subscriber::set_global_default(subscriber).expect("no global subscriber has been set");
 
+    // Check the payment address
+    if net::FEE_SCRIPT.is_none() && SETTINGS.payments.pricing.price(u32::MAX as u64) > 0 {
+        warn!("pricing is configured without a payment address, metadata will be free");
+    }
+
     // Initialize database
     let db = Database::try_new(&SETTINGS.db_path).expect("failed to open database");
 
@@ -80,172 +67,185 @@ async fn main() {
     connector.set_keepalive(Some(Duration::from_secs(SETTINGS.peering.keep_alive)));
     connector.set_connect_timeout(Some(Duration::from_secs(SETTINGS.peering.timeout)));
 
-    // Setup peer state
-    let peer_handler = PeerHandler::new(peers);
-    if let Err(err) = peer_handler.inflate().await {
-        error!(message = "failed to inflate peer list", error = %err)
+    // Client certificates are verified by a TLS terminating proxy, which must be trusted
+    if SETTINGS.peering.tls.require_client_cert && SETTINGS.rate_limits.trusted_proxies.is_empty() {
+        panic!("peering.tls.require_client_cert requires a proxy in rate_limits.trusted_proxies");
+    }
+
+    // Setup peer state, running standalone if peering is disabled
+    let peer_handler = if SETTINGS.peering.enabled {
+        PeerHandler::new(peers)
+    } else {
+        info!(peering = false);
+        PeerHandler::new(vec![])
     };
+    if SETTINGS.peering.enabled {
+        // Drop peers removed or banned by an operator
+        peer_handler.load_exclusions(&db).await.unwrap(); // Unrecoverable
 
-    // Persist peers
-    if let Err(err) = peer_handler.persist(&db).await {
-        error!(message = "failed to persist peers to database", error = %err);
-    }
+        if let Err(err) = peer_handler.inflate().await {
+            error!(message = "failed to inflate peer list", error = %err)
+        };
 
-    // Token cache
-    let token_cache = TokenCache::default();
+        // Persist peers
+        if let Err(err) = peer_handler.persist(&db).await {
+            error!(message = "failed to persist peers to database", error = %err);
+        }
 
-    // Setup ZMQ stream
-    let mut subscriber = async_zmq::subscribe(&SETTINGS.bitcoin_rpc.zmq_address)
-        .unwrap()
-        .connect()
-        .unwrap();
-    subscriber.set_subscribe("hashblock").unwrap(); // Unrecoverable
+        // Announce to seeds
+        if SETTINGS.peering.announce {
+            peer_handler.announce(&SETTINGS.public_url).await;
+        }
 
-    // Start broadcast heartbeat
-    let token_cache_inner = token_cache.clone();
-    let peer_handler_inner = peer_handler.clone();
-    let db_inner = db.clone();
-    let broadcast_heartbeat = || async move {
-        while let Some(val) = subscriber.next().await {
-            if let Ok(inner) = val {
-                if let Some(block) = inner.get(1) {
-                    info!(message = "found block", block_id = %hex::encode(block.as_ref()));
-                    token_cache_inner
-                        .broadcast_block(&peer_handler_inner, &db_inner)
-                        .await;
+        // Start peer crawler
+        let peer_handler_inner = peer_handler.clone();
+        let db_inner = db.clone();
+        let peer_crawler = || async move {
+            let mut interval =
+                time::interval(Duration::from_millis(SETTINGS.peering.crawl_interval));
+            interval.tick().await; // The first tick completes immediately
+            loop {
+                interval.tick().await;
+                if let Err(err) = peer_handler_inner.inflate().await {
+                    error!(message = "failed to crawl peers", error = %err);
+                    continue;
+                }
+                if let Err(err) = peer_handler_inner.persist(&db_inner).await {
+                    error!(message = "failed to persist peers to database", error = %err);
                 }
             }
-        }
-    };
-    tokio::spawn(broadcast_heartbeat());
+        };
+        tokio::spawn(peer_crawler());
+    }
+
+    // Broadcast queue, persisted in the database and resumed on the next block after a restart
+    let token_cache = TokenCache::new(db.clone());
 
-    // Peer state
-    let peer_handler = warp::any().map(move || peer_handler.clone());
+    // Bucket digests served to peers, recomputed at most once per block
+    let digest_cache = DigestCache::new(db.clone());
 
-    // Database state
-    let db_state = warp::any().map(move || db.clone());
+    // Metadata awaiting token confirmations, persisted in the database
+    let pending_queue = PendingQueue::new(db.clone());
 
-    // Initialize bitcoin client
+    // Initialize bitcoin node
     let bitcoin_client = BitcoinClient::new(
         SETTINGS.bitcoin_rpc.address.clone(),
         SETTINGS.bitcoin_rpc.username.clone(),
         SETTINGS.bitcoin_rpc.password.clone(),
     );
+    let block_notifier = BlockNotifier::default();
+    let block_source = match SETTINGS.bitcoin_rpc.block_source {
+        BlockSourceKind::Zmq => BlockSource::Zmq,
+        BlockSourceKind::Poll => {
+            BlockSource::Poll(Duration::from_millis(SETTINGS.bitcoin_rpc.poll_interval))
+        }
+        BlockSourceKind::Notify => {
+            if SETTINGS.bitcoin_rpc.notify_secret.is_none() {
+                panic!("bitcoin_rpc.block_source \"notify\" requires bitcoin_rpc.notify_secret");
+            }
+            BlockSource::Notify(block_notifier.clone())
+        }
+    };
+    let node = BitcoindNode::new(bitcoin_client, SETTINGS.bitcoin_rpc.zmq_address.clone())
+        .with_block_source(block_source);
+    match node.get_block_count().await {
+        Ok(height) => token_cache.set_height(height),
+        Err(err) => error!(message = "failed to get block height", error = %err),
+    }
 
-    // Address string converter
-    let addr_base = warp::path::param().and_then(|addr_str: String| async move {
-        net::address_decode(&addr_str).map_err(warp::reject::custom)
-    });
-
-    // Token generator
-    let token_scheme = Arc::new(ChainCommitmentScheme::from_client(bitcoin_client.clone()));
-    let token_scheme_state = warp::any().map(move || token_scheme.clone());
-
-    // Token cache state
-    let token_cache_state = warp::any().map(move || token_cache.clone());
-
-    // Bitcoin client state
-    let bitcoin_client_state = warp::any().map(move || bitcoin_client.clone());
-
-    // Protection
-    let addr_protected = addr_base
-        .clone()
-        .and(warp::body::content_length_limit(
-            SETTINGS.limits.metadata_size,
-        ))
-        .and(warp::body::bytes())
-        .and(warp::header::headers_cloned())
-        .and(token_scheme_state.clone())
-        .and_then(move |addr, body, headers, token_scheme| {
-            protection::pop_protection(addr, body, headers, token_scheme)
-                .map_err(warp::reject::custom)
-        })
-        .untuple_one();
-
-    // Metadata handlers
-    let metadata_get = warp::path(METADATA_PATH)
-        .and(addr_base)
-        .and(warp::get())
-        .and(warp::header::headers_cloned())
-        .and(db_state.clone())
-        .and(peer_handler.clone())
-        .and_then(move |addr, headers, db, peer_handler| {
-            net::get_metadata(addr, headers, db, peer_handler).map_err(warp::reject::custom)
-        });
-    let metadata_put = warp::path(METADATA_PATH)
-        .and(addr_protected)
-        .and(warp::put())
-        .and(warp::body::content_length_limit(
-            SETTINGS.limits.metadata_size,
-        ))
-        .and(db_state.clone())
-        .and(token_cache_state)
-        .and_then(
-            move |addr, auth_wrapper_raw, auth_wrapper, raw_token, db, token_cache| {
-                net::put_metadata(
-                    addr,
-                    auth_wrapper_raw,
-                    auth_wrapper,
-                    raw_token,
-                    db,
-                    token_cache,
-                )
-                .map_err(warp::reject::custom)
-            },
-        );
-
-    // Peer handler
-    let peers_get = warp::path(PEERS_PATH)
-        .and(warp::get())
-        .and(peer_handler)
-        .and_then(move |peer_handler| net::get_peers(peer_handler).map_err(warp::reject::custom));
-
-    // Payment handler
-    let payments = warp::path(PAYMENTS_PATH)
-        .and(warp::post())
-        .and(warp::header::headers_cloned())
-        .and(warp::body::content_length_limit(
-            SETTINGS.limits.payment_size,
-        ))
-        .and(warp::body::bytes())
-        .and_then(move |headers, body| {
-            preprocess_payment(headers, body)
-                .map_err(payments::PaymentError::Preprocess)
-                .map_err(warp::reject::custom)
-        })
-        .and(bitcoin_client_state.clone())
-        .and_then(move |payment, bitcoin_client| async move {
-            net::process_payment(payment, bitcoin_client)
-                .await
-                .map_err(warp::reject::custom)
-        });
-
-    // Root handler
-    let root = warp::path::end()
-        .and(warp::get())
-        .and(warp::fs::file("./static/index.html"));
+    // Setup block and transaction streams
+    let mut blocks = node.subscribe_blocks().unwrap(); // Unrecoverable
+    let mut transactions = node.subscribe_transactions().unwrap(); // Unrecoverable
+
+    // Watch stored tokens for reorgs and double-spends
+    let validation_cache = ValidationCache::new(SETTINGS.tokens.cache_size);
+    let token_watcher = TokenWatcher::new(node.clone(), db.clone(), validation_cache.clone());
+    let token_watcher_inner = token_watcher.clone();
+    let transaction_watcher = || async move {
+        while let Some(raw_tx) = transactions.next().await {
+            token_watcher_inner.process_transaction(&raw_tx).await;
+        }
+    };
+    tokio::spawn(transaction_watcher());
+
+    // Start metadata synchronization, reconciling with one peer per round
+    if SETTINGS.peering.enabled && SETTINGS.peering.sync_interval != 0 {
+        let peer_handler_inner = peer_handler.clone();
+        let db_inner = db.clone();
+        let node_inner = node.clone();
+        let token_cache_inner = token_cache.clone();
+        let token_scheme = keyserver::token_scheme(node.clone(), validation_cache.clone());
+        let synchronizer = || async move {
+            let mut interval =
+                time::interval(Duration::from_millis(SETTINGS.peering.sync_interval));
+            let mut round = 0;
+            loop {
+                interval.tick().await;
+                let uris = peer_handler_inner.get_urls().await;
+                if uris.is_empty() {
+                    continue;
+                }
+                let uri = &uris[round % uris.len()];
+                round += 1;
+                match peer_handler_inner
+                    .sync_metadata(
+                        uri,
+                        &db_inner,
+                        &token_scheme,
+                        &node_inner,
+                        &token_cache_inner,
+                    )
+                    .await
+                {
+                    Ok(stored) => info!(message = "synchronized metadata", peer = %uri, stored),
+                    Err(err) => {
+                        warn!(message = "failed to synchronize metadata", peer = %uri, error = %err)
+                    }
+                }
+            }
+        };
+        tokio::spawn(synchronizer());
+    }
 
-    // CORs
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_methods(vec![Method::GET, Method::PUT, Method::POST, Method::DELETE])
-        .allow_headers(vec![header::AUTHORIZATION, header::CONTENT_TYPE])
-        .expose_headers(vec![
-            header::AUTHORIZATION,
-            header::ACCEPT,
-            header::LOCATION,
-        ])
-        .build();
+    // Start broadcast heartbeat
+    let token_cache_inner = token_cache.clone();
+    let peer_handler_inner = peer_handler.clone();
+    let node_inner = node.clone();
+    let pending_queue_inner = pending_queue.clone();
+    let digest_cache_inner = digest_cache.clone();
+    let broadcast_heartbeat = || async move {
+        while let Some(block_hash) = blocks.next().await {
+            info!(message = "found block", block_id = %hex::encode(&block_hash));
+            token_watcher.process_block().await;
+            digest_cache_inner.clear().await;
+            pending_queue_inner
+                .process(&node_inner, &token_cache_inner)
+                .await;
+            peer_handler_inner.refresh_peers().await;
+            match node_inner.get_block_count().await {
+                Ok(height) => {
+                    token_cache_inner
+                        .broadcast_block(&peer_handler_inner, height)
+                        .await
+                }
+                Err(err) => error!(message = "failed to get block height", error = %err),
+            }
+        }
+        error!("block notifications ended, metadata will no longer be broadcast");
+    };
+    tokio::spawn(broadcast_heartbeat());
 
     // Init REST API
-    let rest_api = root
-        .or(payments)
-        .or(metadata_get)
-        .or(metadata_put)
-        .or(peers_get)
-        .recover(net::handle_rejection)
-        .with(cors)
-        .with(warp::trace::request());
+    let rest_api = keyserver::rest_api(
+        db,
+        node,
+        peer_handler,
+        token_cache,
+        digest_cache,
+        pending_queue,
+        validation_cache,
+        block_notifier,
+    );
 
     // If monitoring is enabled
     #[cfg(feature = "monitoring")]
@@ -2,6 +2,10 @@ pub mod database {
     include!(concat!(env!("OUT_DIR"), "/database.rs"));
 }
 
+pub mod sync {
+    include!(concat!(env!("OUT_DIR"), "/sync.rs"));
+}
+
 pub use cashweb::keyserver;
 
 pub use cashweb::auth_wrapper as wrapper;
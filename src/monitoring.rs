@@ -1,5 +1,5 @@
 use lazy_static::lazy_static;
-use prometheus::{CounterVec, HistogramVec};
+use prometheus::{CounterVec, HistogramVec, IntCounterVec};
 use warp::filters::log::Info;
 
 #[cfg(feature = "monitoring")]
@@ -82,6 +82,14 @@ lazy_static! {
     )
     .unwrap();
     pub static ref HTTP_ELAPSED: RequestDurationHistogram = RequestDurationHistogram::from(&HTTP_ELAPSED_VEC);
+
+    // Token validation cache lookups
+    pub static ref VALIDATION_CACHE_TOTAL: IntCounterVec = prometheus::register_int_counter_vec!(
+        "token_validation_cache_total",
+        "Total number of token validation cache lookups, by hit or miss.",
+        &["result"]
+    )
+    .unwrap();
 }
 
 pub fn measure(info: Info) {
@@ -0,0 +1,268 @@
+use std::{
+    fmt,
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::Bytes;
+use hyper::{http::uri::InvalidUri, Request, Uri};
+use rocksdb::Error as RocksError;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tower_service::Service;
+use tracing::info;
+use warp::{
+    http::{header::CONTENT_TYPE, Response},
+    hyper::Body,
+    reject::Reject,
+};
+
+use super::{api_keys, ApiKeyError, IntoResponse};
+use crate::{db::Database, peering::PeerHandler, settings::ApiKeyScope, SETTINGS};
+
+/// Maximum size of an admin request.
+pub const ADMIN_REQUEST_LIMIT: u64 = 1_000;
+
+/// Duration of a ban when none is given, in seconds.
+const DEFAULT_BAN_DURATION: u64 = 24 * 60 * 60;
+
+/// Maximum duration of a ban, in seconds.
+const MAX_BAN_DURATION: u64 = 365 * 24 * 60 * 60;
+
+/// Request identifying a peer.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerRequest {
+    pub url: String,
+}
+
+/// Request to ban a peer.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BanPeerRequest {
+    pub url: String,
+    /// Duration of the ban in seconds, defaults to a day and is capped at a year.
+    pub duration: Option<u64>,
+}
+
+/// Known peer along with its health statistics.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerSummary {
+    pub url: String,
+    pub seed: bool,
+    /// Whether the peer is currently sampled.
+    pub active: bool,
+    pub successes: u64,
+    pub failures: u64,
+    pub invalid: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    pub score: i32,
+    pub bans: u32,
+    /// Seconds remaining on the current ban.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub banned_for: Option<u64>,
+}
+
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error("missing api key")]
+    MissingApiKey,
+    #[error(transparent)]
+    ApiKey(ApiKeyError),
+    #[error("peering not supported")]
+    PeeringUnavailible,
+    #[error("failed to decode request: {0}")]
+    JsonDecode(serde_json::Error),
+    #[error("invalid peer url: {0}")]
+    Uri(InvalidUri),
+    #[error("unknown peer")]
+    UnknownPeer,
+    #[error("failed to crawl peers: {0}")]
+    Crawl(String),
+    #[error("failed to persist peers: {0}")]
+    Database(RocksError),
+}
+
+impl Reject for AdminError {}
+
+impl IntoResponse for AdminError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::MissingApiKey => 401,
+            Self::ApiKey(err) => err.to_status(),
+            Self::PeeringUnavailible => 501,
+            Self::JsonDecode(_) | Self::Uri(_) => 400,
+            Self::UnknownPeer => 404,
+            Self::Crawl(_) => 502,
+            Self::Database(_) => 500,
+        }
+    }
+}
+
+/// Authorize an admin request using an API key with the admin scope, exempt from its quota.
+pub async fn authorize_admin(key: Option<String>) -> Result<(), AdminError> {
+    let key = key.ok_or(AdminError::MissingApiKey)?;
+    let api_key =
+        api_keys::authorize_api_key(&key, ApiKeyScope::Admin).map_err(AdminError::ApiKey)?;
+    info!(message = "admin request", name = %api_key.name);
+    Ok(())
+}
+
+/// Summarize the known peers.
+async fn summarize_peers<S: Clone>(peer_handler: &PeerHandler<S>) -> Vec<PeerSummary> {
+    let seed_urls: Vec<Uri> = SETTINGS
+        .peering
+        .peers
+        .iter()
+        .filter_map(|peer_str| peer_str.parse().ok())
+        .collect();
+    let active_urls = peer_handler.get_urls().await;
+    peer_handler
+        .get_known_urls()
+        .await
+        .into_iter()
+        .map(|uri| {
+            let stats = peer_handler
+                .get_health()
+                .get_stats(&uri)
+                .unwrap_or_default();
+            let banned_for = stats
+                .banned_until
+                .filter(|_| stats.is_banned())
+                .map(|banned_until| {
+                    banned_until
+                        .saturating_duration_since(Instant::now())
+                        .as_secs()
+                });
+            PeerSummary {
+                url: uri.to_string(),
+                seed: seed_urls.contains(&uri),
+                active: active_urls.contains(&uri),
+                successes: stats.successes,
+                failures: stats.failures,
+                invalid: stats.invalid,
+                latency_ms: stats.latency.map(|latency| latency.as_millis() as u64),
+                score: stats.score,
+                bans: stats.bans,
+                banned_for,
+            }
+        })
+        .collect()
+}
+
+/// Respond with the known peers.
+async fn peers_response<S: Clone>(peer_handler: &PeerHandler<S>) -> Response<Body> {
+    let summaries = summarize_peers(peer_handler).await;
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&summaries).unwrap())) // This is safe
+        .unwrap()
+}
+
+/// Persist the active peers.
+async fn persist_peers<S: Clone>(
+    peer_handler: &PeerHandler<S>,
+    database: &Database,
+) -> Result<(), AdminError> {
+    peer_handler
+        .persist(database)
+        .await
+        .map_err(AdminError::Database)
+}
+
+/// Handles requests listing the known peers with their statistics.
+pub async fn get_admin_peers<S: Clone>(
+    peer_handler: PeerHandler<S>,
+) -> Result<Response<Body>, AdminError> {
+    Ok(peers_response(&peer_handler).await)
+}
+
+/// Handles requests adding a peer.
+pub async fn post_admin_peer<S: Clone>(
+    body: Bytes,
+    peer_handler: PeerHandler<S>,
+    database: Database,
+) -> Result<Response<Body>, AdminError> {
+    if !SETTINGS.peering.enabled {
+        return Err(AdminError::PeeringUnavailible);
+    }
+
+    let request: PeerRequest = serde_json::from_slice(&body).map_err(AdminError::JsonDecode)?;
+    let uri: Uri = request.url.parse().map_err(AdminError::Uri)?;
+    peer_handler
+        .include(&database, &uri)
+        .map_err(AdminError::Database)?;
+    if peer_handler.add_peer(uri.clone()).await {
+        info!(message = "added peer", peer = %uri);
+    }
+    persist_peers(&peer_handler, &database).await?;
+    Ok(peers_response(&peer_handler).await)
+}
+
+/// Handles requests removing a peer.
+pub async fn delete_admin_peer<S: Clone>(
+    request: PeerRequest,
+    peer_handler: PeerHandler<S>,
+    database: Database,
+) -> Result<Response<Body>, AdminError> {
+    let uri: Uri = request.url.parse().map_err(AdminError::Uri)?;
+    if !peer_handler.remove_peer(&uri).await {
+        return Err(AdminError::UnknownPeer);
+    }
+    peer_handler
+        .exclude(&database, uri.clone(), None)
+        .await
+        .map_err(AdminError::Database)?;
+    info!(message = "removed peer", peer = %uri);
+    persist_peers(&peer_handler, &database).await?;
+    Ok(peers_response(&peer_handler).await)
+}
+
+/// Handles requests banning a peer.
+pub async fn post_admin_ban<S: Clone>(
+    body: Bytes,
+    peer_handler: PeerHandler<S>,
+    database: Database,
+) -> Result<Response<Body>, AdminError> {
+    let request: BanPeerRequest = serde_json::from_slice(&body).map_err(AdminError::JsonDecode)?;
+    let uri: Uri = request.url.parse().map_err(AdminError::Uri)?;
+    if !peer_handler.get_known_urls().await.contains(&uri) {
+        return Err(AdminError::UnknownPeer);
+    }
+    let duration = request
+        .duration
+        .unwrap_or(DEFAULT_BAN_DURATION)
+        .min(MAX_BAN_DURATION);
+    let duration = Duration::from_secs(duration);
+    peer_handler.get_health().ban(&uri, duration);
+    peer_handler
+        .exclude(&database, uri, Some(SystemTime::now() + duration))
+        .await
+        .map_err(AdminError::Database)?;
+    persist_peers(&peer_handler, &database).await?;
+    Ok(peers_response(&peer_handler).await)
+}
+
+/// Handles requests forcing a peer crawl.
+pub async fn post_admin_crawl<S>(
+    peer_handler: PeerHandler<S>,
+    database: Database,
+) -> Result<Response<Body>, AdminError>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S: Send + Clone + 'static,
+    S::Future: Send,
+    S::Error: fmt::Debug + Send + fmt::Display,
+{
+    if !SETTINGS.peering.enabled {
+        return Err(AdminError::PeeringUnavailible);
+    }
+
+    peer_handler
+        .inflate()
+        .await
+        .map_err(|err| AdminError::Crawl(err.to_string()))?;
+    persist_peers(&peer_handler, &database).await?;
+    Ok(peers_response(&peer_handler).await)
+}
@@ -0,0 +1,117 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use ring::constant_time::verify_slices_are_equal;
+use thiserror::Error;
+use warp::reject::Reject;
+
+use crate::{
+    net::IntoResponse,
+    settings::{ApiKey, ApiKeyScope},
+    SETTINGS,
+};
+
+pub const API_KEY: &str = "Api-Key";
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+lazy_static! {
+    // Uses of each API key, by name, during the current day
+    static ref USAGE: DashMap<String, (u64, u64)> = DashMap::new();
+}
+
+#[derive(Debug, Error)]
+pub enum ApiKeyError {
+    #[error("unknown api key")]
+    Unknown,
+    #[error("api key lacks the {0:?} scope")]
+    Scope(ApiKeyScope),
+    #[error("api key daily quota of {0} exhausted")]
+    QuotaExhausted(u64),
+}
+
+impl Reject for ApiKeyError {}
+
+impl IntoResponse for ApiKeyError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::Unknown => 401,
+            Self::Scope(_) => 403,
+            Self::QuotaExhausted(_) => 429,
+        }
+    }
+}
+
+/// Authorize an API key within a scope.
+pub fn authorize_api_key(key: &str, scope: ApiKeyScope) -> Result<&'static ApiKey, ApiKeyError> {
+    let api_key = SETTINGS
+        .api_keys
+        .iter()
+        .find(|api_key| verify_slices_are_equal(api_key.key.as_bytes(), key.as_bytes()).is_ok())
+        .ok_or(ApiKeyError::Unknown)?;
+    if !api_key.scopes.contains(&scope) {
+        return Err(ApiKeyError::Scope(scope));
+    }
+    Ok(api_key)
+}
+
+fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap() // This is safe
+        .as_secs()
+        / SECONDS_PER_DAY
+}
+
+/// Reserve a use of an API key from its daily quota, refunded if the use fails.
+pub fn reserve_quota(api_key: &ApiKey) -> Result<(), ApiKeyError> {
+    if let Some(daily_quota) = api_key.daily_quota {
+        let day = today();
+        let mut usage = USAGE.entry(api_key.name.clone()).or_insert((day, 0));
+        if usage.0 != day {
+            *usage = (day, 0);
+        }
+        if usage.1 >= daily_quota {
+            return Err(ApiKeyError::QuotaExhausted(daily_quota));
+        }
+        usage.1 += 1;
+    }
+    Ok(())
+}
+
+/// Refund a use reserved by [`reserve_quota`], unless the quota has since been reset.
+pub fn refund_quota(api_key: &ApiKey) {
+    if api_key.daily_quota.is_some() {
+        if let Some(mut usage) = USAGE.get_mut(&api_key.name) {
+            if usage.0 == today() {
+                usage.1 = usage.1.saturating_sub(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota() {
+        let api_key = ApiKey {
+            name: "quota".to_string(),
+            key: "key".to_string(),
+            scopes: vec![ApiKeyScope::Metadata],
+            daily_quota: Some(2),
+        };
+        assert!(reserve_quota(&api_key).is_ok());
+        assert!(reserve_quota(&api_key).is_ok());
+        assert!(matches!(
+            reserve_quota(&api_key),
+            Err(ApiKeyError::QuotaExhausted(2))
+        ));
+
+        refund_quota(&api_key);
+        assert!(reserve_quota(&api_key).is_ok());
+        assert!(reserve_quota(&api_key).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::net::IpAddr;
+
+use bytes::Bytes;
+use ring::constant_time::verify_slices_are_equal;
+use thiserror::Error;
+use warp::{http::Response, hyper::Body, reject::Reject};
+
+use super::IntoResponse;
+use crate::{node::BlockNotifier, settings::BlockSourceKind, SETTINGS};
+
+/// Maximum size of a block notification.
+pub const BLOCK_NOTIFY_LIMIT: u64 = 128;
+
+/// Header carrying the block notification secret.
+pub const NOTIFY_SECRET: &str = "Notify-Secret";
+
+#[derive(Debug, Error)]
+pub enum BlockNotifyError {
+    #[error("block notifications are disabled")]
+    Disabled,
+    #[error("block notifications are only accepted from loopback addresses")]
+    Forbidden,
+    #[error("missing or incorrect notification secret")]
+    Unauthorized,
+    #[error("failed to decode block hash: {0}")]
+    Decode(hex::FromHexError),
+    #[error("unexpected block hash length")]
+    Length,
+}
+
+impl Reject for BlockNotifyError {}
+
+impl IntoResponse for BlockNotifyError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::Disabled => 404,
+            Self::Forbidden => 403,
+            Self::Unauthorized => 401,
+            Self::Decode(_) | Self::Length => 400,
+        }
+    }
+}
+
+/// Handles block notification POST requests, such as those made using bitcoind's `-blocknotify`.
+///
+/// The body is the hex encoded block hash. Loopback addresses may be reached through a local
+/// reverse proxy, so the notification secret is also required.
+pub async fn post_block_notify(
+    body: Bytes,
+    client: IpAddr,
+    secret: Option<String>,
+    block_notifier: BlockNotifier,
+) -> Result<Response<Body>, BlockNotifyError> {
+    if SETTINGS.bitcoin_rpc.block_source != BlockSourceKind::Notify {
+        return Err(BlockNotifyError::Disabled);
+    }
+    if !client.is_loopback() {
+        return Err(BlockNotifyError::Forbidden);
+    }
+    let expected = SETTINGS.bitcoin_rpc.notify_secret.as_deref();
+    match (secret, expected) {
+        (Some(secret), Some(expected))
+            if verify_slices_are_equal(secret.as_bytes(), expected.as_bytes()).is_ok() => {}
+        _ => return Err(BlockNotifyError::Unauthorized),
+    }
+
+    let block_hash =
+        hex::decode(String::from_utf8_lossy(&body).trim()).map_err(BlockNotifyError::Decode)?;
+    if block_hash.len() != 32 {
+        return Err(BlockNotifyError::Length);
+    }
+    block_notifier.notify(block_hash);
+
+    Ok(Response::builder().body(Body::empty()).unwrap())
+}
@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use warp::{
+    http::{header::CONTENT_TYPE, Response},
+    hyper::Body,
+};
+
+use crate::SETTINGS;
+
+/// Optional features supported by this keyserver.
+pub const FEATURES: &[&str] = &["announce", "sync", "invoices", "token_validation"];
+
+/// Version, network and limits of a keyserver, used by peers to check compatibility.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyserverInfo {
+    pub version: String,
+    pub network: String,
+    /// Maximum metadata size accepted, in bytes.
+    pub metadata_size: u64,
+    /// Whether peering is enabled.
+    pub peering: bool,
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum Incompatibility {
+    #[error("peer is on network {0}")]
+    Network(String),
+    #[error("peer accepts metadata of at most {0} bytes")]
+    MetadataSize(u64),
+}
+
+impl KeyserverInfo {
+    /// Info describing this keyserver.
+    pub fn local() -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            network: SETTINGS.network.clone(),
+            metadata_size: SETTINGS.limits.metadata_size,
+            peering: SETTINGS.peering.enabled,
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+
+    /// Check a peer can exchange metadata with the given keyserver.
+    ///
+    /// The peer must be on the same network and accept any metadata the keyserver accepts.
+    pub fn check_compatible(&self, local: &KeyserverInfo) -> Result<(), Incompatibility> {
+        if self.network != local.network {
+            return Err(Incompatibility::Network(self.network.clone()));
+        }
+        if self.metadata_size < local.metadata_size {
+            return Err(Incompatibility::MetadataSize(self.metadata_size));
+        }
+        Ok(())
+    }
+}
+
+/// Handles GET requests for the keyserver info.
+pub fn get_info() -> Response<Body> {
+    let info = KeyserverInfo::local();
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&info).unwrap())) // This is safe
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compatibility() {
+        let local = KeyserverInfo {
+            version: "0.2.1".to_string(),
+            network: "mainnet".to_string(),
+            metadata_size: 5_000,
+            peering: true,
+            features: vec!["sync".to_string()],
+        };
+
+        let peer: KeyserverInfo = serde_json::from_str(
+            r#"{"version":"0.3.0","network":"mainnet","metadataSize":10000,"peering":true}"#,
+        )
+        .unwrap();
+        assert!(peer.features.is_empty());
+        assert!(peer.check_compatible(&local).is_ok());
+
+        let testnet_peer = KeyserverInfo {
+            network: "testnet".to_string(),
+            ..local.clone()
+        };
+        assert!(matches!(
+            testnet_peer.check_compatible(&local),
+            Err(Incompatibility::Network(_))
+        ));
+
+        let limited_peer = KeyserverInfo {
+            metadata_size: 1_000,
+            ..local.clone()
+        };
+        assert!(matches!(
+            limited_peer.check_compatible(&local),
+            Err(Incompatibility::MetadataSize(1_000))
+        ));
+    }
+}
@@ -0,0 +1,59 @@
+use http::header::{HeaderMap, CONTENT_TYPE};
+use image::{DynamicImage, ImageOutputFormat, Luma};
+use qrcode::QrCode;
+use warp::{http::Response, hyper::Body};
+
+use super::{
+    payments::{
+        construct_payment_request, payment_uri, PaymentFormat, PaymentRequestError,
+        MERCHANT_DATA_SIZE,
+    },
+    PAYMENT_URI,
+};
+
+pub const PNG: &str = "image/png";
+
+/// Decode an invoice ID into the merchant data.
+fn decode_invoice_id(invoice_id: &str) -> Result<Vec<u8>, PaymentRequestError> {
+    let merchant_data = hex::decode(invoice_id).map_err(PaymentRequestError::InvoiceIdDecode)?;
+    if merchant_data.len() != MERCHANT_DATA_SIZE {
+        return Err(PaymentRequestError::IncorrectLengthPreimage);
+    }
+    Ok(merchant_data)
+}
+
+/// Handles invoice GET requests.
+pub async fn get_invoice(
+    invoice_id: String,
+    headers: HeaderMap,
+) -> Result<Response<Body>, PaymentRequestError> {
+    let merchant_data = decode_invoice_id(&invoice_id)?;
+    let payment_uri = payment_uri(&merchant_data);
+
+    let format = PaymentFormat::from_request_headers(&headers);
+    let (content_type, payment_request) = construct_payment_request(merchant_data, format);
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(PAYMENT_URI, payment_uri)
+        .body(Body::from(payment_request))
+        .unwrap())
+}
+
+/// Handles invoice QR code GET requests, rendering the BIP21 URI of the invoice as a PNG.
+pub async fn get_invoice_qr(invoice_id: String) -> Result<Response<Body>, PaymentRequestError> {
+    let merchant_data = decode_invoice_id(&invoice_id)?;
+    let payment_uri = payment_uri(&merchant_data);
+
+    let qr_code = QrCode::new(payment_uri).map_err(PaymentRequestError::QrCode)?;
+    let qr_image = qr_code.render::<Luma<u8>>().build();
+    let mut raw_png = Vec::new();
+    DynamicImage::ImageLuma8(qr_image)
+        .write_to(&mut raw_png, ImageOutputFormat::Png)
+        .unwrap(); // This is safe
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, PNG)
+        .body(Body::from(raw_png))
+        .unwrap())
+}
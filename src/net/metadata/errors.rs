@@ -40,6 +40,8 @@ impl IntoResponse for PutMetadataError {
 pub enum GetMetadataError {
     #[error("not found")]
     NotFound,
+    #[error("metadata token has been invalidated")]
+    Invalidated,
     #[error("failed to read from database: {0}")]
     Database(RocksError),
 }
@@ -56,6 +58,7 @@ impl IntoResponse for GetMetadataError {
     fn to_status(&self) -> u16 {
         match self {
             Self::NotFound => 404,
+            Self::Invalidated => 410,
             Self::Database(_) => 500,
         }
     }
@@ -1,26 +1,36 @@
 pub mod errors;
+pub mod pending;
 
 use std::fmt;
 
 use bitcoincash_addr::Address;
 use bytes::Bytes;
+use cashweb::keyserver_client::services::{GetMetadataError as GetPeerMetadataError, SampleError};
 use http::{
     header::{HeaderMap, HeaderValue, AUTHORIZATION},
-    Request,
+    Request, Uri,
 };
 use prost::Message as _;
 use tokio::task;
 use tower_service::Service;
+use tracing::info;
 use warp::{http::Response, hyper::Body};
 
-use super::{HEADER_VALUE_FALSE, SAMPLING};
+use super::{api_keys, Authorization, HEADER_VALUE_FALSE, HEADER_VALUE_TRUE, SAMPLING};
 use crate::{
     db::Database,
-    models::{database::DatabaseWrapper, wrapper::AuthWrapper},
-    peering::{PeerHandler, TokenCache},
+    models::{
+        database::{DatabaseWrapper, WatchRecord},
+        wrapper::AuthWrapper,
+    },
+    peering::{is_invalid_metadata, PeerHandler, TokenCache},
+    settings::TokenSchemeKind,
     SETTINGS,
 };
 pub use errors::*;
+pub use pending::*;
+
+pub const OPERATOR_PUBLISHED: &str = "Operator-Published";
 
 /// Handles metadata GET requests.
 pub async fn get_metadata<S>(
@@ -42,8 +52,20 @@ where
 
     // If found in the database
     if let Some(some) = wrapper_opt {
+        // Withhold metadata whose token has been invalidated, without sampling peers for it
+        if some.invalid {
+            return Err(GetMetadataError::Invalidated);
+        }
         let raw_auth_wrapper = some.serialized_auth_wrapper;
 
+        // Flag metadata published by the operator, which carries no token
+        if some.operator_published {
+            return Ok(Response::builder()
+                .header(OPERATOR_PUBLISHED, HEADER_VALUE_TRUE)
+                .body(Body::from(raw_auth_wrapper))
+                .unwrap());
+        }
+
         // Encode token
         let raw_token = some.token;
         let url_safe_config = base64::Config::new(base64::CharacterSet::UrlSafe, false);
@@ -55,19 +77,21 @@ where
             .unwrap()); // TODO: Headers
     }
 
-    // If MAX_FORWARDS is 0 then don't sample peers
-    if headers.get(SAMPLING) == Some(&HeaderValue::from_static(HEADER_VALUE_FALSE)) {
+    // If sampling is disabled, by the request or the server, then don't sample peers
+    if !SETTINGS.peering.enabled
+        || headers.get(SAMPLING) == Some(&HeaderValue::from_static(HEADER_VALUE_FALSE))
+    {
         return Err(GetMetadataError::NotFound);
     }
 
     // Sample peers
     let addr_str = addr.encode().unwrap();
     match peer_handler
-        .get_keyserver_manager()
-        .uniform_sample_metadata(&addr_str, SETTINGS.peering.pull_fan_size)
+        .sample_metadata(&addr_str, SETTINGS.peering.pull_fan_size)
         .await
     {
         Ok(sample_response) => {
+            record_invalid_metadata(&peer_handler, &sample_response.errors);
             if let Some((_, metadata_package)) = sample_response.response {
                 let token = metadata_package.token;
                 let raw_auth_wrapper = metadata_package.raw_auth_wrapper;
@@ -79,43 +103,128 @@ where
                 Err(GetMetadataError::NotFound)
             }
         }
+        Err(SampleError::Sample(errors)) => {
+            record_invalid_metadata(&peer_handler, &errors);
+            Err(GetMetadataError::NotFound)
+        }
         _ => Err(GetMetadataError::NotFound),
     }
 }
 
+/// Penalize peers which served invalid metadata.
+fn record_invalid_metadata<S, E>(
+    peer_handler: &PeerHandler<S>,
+    errors: &[(Uri, GetPeerMetadataError<E>)],
+) where
+    S: Clone,
+    E: fmt::Debug + fmt::Display,
+{
+    for (uri, err) in errors {
+        if is_invalid_metadata(err) {
+            peer_handler.get_health().record_invalid(uri);
+        }
+    }
+}
+
+/// Verify the signature of an authorization wrapper.
+pub fn verify_auth_wrapper(auth_wrapper: AuthWrapper) -> Result<(), PutMetadataError> {
+    auth_wrapper
+        .parse()
+        .map_err(PutMetadataError::InvalidAuthWrapper)?
+        .verify()
+        .map_err(PutMetadataError::VerifyAuthWrapper)
+}
+
 /// Handles metadata PUT requests.
 pub async fn put_metadata(
     addr: Address,
     auth_wrapper_raw: Bytes,
     auth_wrapper: AuthWrapper,
-    token_raw: Vec<u8>,
+    authorization: Authorization,
     db_data: Database,
     token_cache: TokenCache,
 ) -> Result<Response<Body>, PutMetadataError> {
+    let api_key = match authorization {
+        Authorization::ApiKey(api_key) => Some(api_key),
+        Authorization::Token(_) => None,
+    };
+    let result = store_metadata(
+        addr,
+        auth_wrapper_raw,
+        auth_wrapper,
+        authorization,
+        db_data,
+        token_cache,
+    )
+    .await;
+
+    // Refund the use of the API key reserved by `pop_protection` if the metadata wasn't stored
+    if let (Some(api_key), Err(_)) = (api_key, &result) {
+        api_keys::refund_quota(api_key);
+    }
+    result?;
+
+    // Respond
+    Ok(Response::builder().body(Body::empty()).unwrap())
+}
+
+async fn store_metadata(
+    addr: Address,
+    auth_wrapper_raw: Bytes,
+    auth_wrapper: AuthWrapper,
+    authorization: Authorization,
+    db_data: Database,
+    token_cache: TokenCache,
+) -> Result<(), PutMetadataError> {
     // Verify signatures
-    auth_wrapper
-        .parse()
-        .map_err(PutMetadataError::InvalidAuthWrapper)?
-        .verify()
-        .map_err(PutMetadataError::VerifyAuthWrapper)?;
+    verify_auth_wrapper(auth_wrapper)?;
+
+    let (token_raw, operator_published) = match authorization {
+        Authorization::Token(token_raw) => (token_raw, false),
+        Authorization::ApiKey(api_key) => {
+            info!(message = "published using api key", name = %api_key.name);
+            (Vec::new(), true)
+        }
+    };
+
+    // Watch the commitment transaction for reorgs and double-spends, tokens being checked for a
+    // transaction ID by `pop_protection`
+    let watch = match token_raw.get(..32) {
+        Some(tx_id) if SETTINGS.tokens.scheme == TokenSchemeKind::ChainCommitment => {
+            let watch_record = WatchRecord {
+                address: addr.as_body().to_vec(),
+                outpoints: Vec::new(),
+            };
+            Some((tx_id.to_vec(), watch_record))
+        }
+        _ => None,
+    };
 
     // Wrap with database
     let database_wrapper = DatabaseWrapper {
         serialized_auth_wrapper: auth_wrapper_raw.to_vec(),
         token: token_raw,
+        invalid: false,
+        operator_published,
     };
     let mut raw_database_wrapper = Vec::with_capacity(database_wrapper.encoded_len());
     database_wrapper.encode(&mut raw_database_wrapper).unwrap(); // This is safe
 
     // Put to database
     let addr_raw = addr.as_body().to_vec();
-    task::spawn_blocking(move || db_data.put_metadata(&addr_raw, &raw_database_wrapper))
-        .await
-        .unwrap()?;
-
-    // Put token to cache
-    token_cache.add_token(addr).await;
+    task::spawn_blocking(move || {
+        db_data.put_metadata(&addr_raw, &raw_database_wrapper)?;
+        match watch {
+            Some((tx_id, watch_record)) => db_data.put_watch(&tx_id, &watch_record),
+            None => Ok(()),
+        }
+    })
+    .await
+    .unwrap()?;
 
-    // Respond
-    Ok(Response::builder().body(Body::empty()).unwrap())
+    // Put token to cache, operator published metadata carries no token so stays local
+    if !operator_published {
+        token_cache.add_token(addr).await?;
+    }
+    Ok(())
 }
@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use bitcoincash_addr::Address;
+use bytes::Bytes;
+use prost::Message as _;
+use rocksdb::Error as RocksError;
+use tokio::{sync::Mutex, task};
+use tracing::{error, info, warn};
+
+use super::put_metadata;
+use crate::{
+    db::Database,
+    models::{database::PendingRecord, wrapper::AuthWrapper},
+    net::Authorization,
+    node::Node,
+    peering::TokenCache,
+    SETTINGS,
+};
+
+/// Metadata update awaiting confirmation of its POP token.
+pub struct PendingPut {
+    pub addr: Address,
+    pub auth_wrapper_raw: Bytes,
+    pub auth_wrapper: AuthWrapper,
+    pub raw_token: Vec<u8>,
+}
+
+/// Queue of metadata updates awaiting confirmation of their POP tokens, persisted in the database.
+///
+/// The queue holds the latest update per address, up to `max_queued` updates in total.
+#[derive(Clone)]
+pub struct PendingQueue {
+    database: Database,
+    lock: Arc<Mutex<()>>,
+}
+
+impl PendingQueue {
+    /// Construct new [`PendingQueue`] over the queue persisted in the database.
+    pub fn new(database: Database) -> Self {
+        Self {
+            database,
+            lock: Default::default(),
+        }
+    }
+
+    /// Queue a metadata update, replacing any pending update to the same address.
+    ///
+    /// Returns false if the queue is full.
+    pub async fn add(&self, pending_put: PendingPut) -> Result<bool, RocksError> {
+        let addr_raw = pending_put.addr.as_body().to_vec();
+        let pending_record = PendingRecord {
+            serialized_auth_wrapper: pending_put.auth_wrapper_raw.to_vec(),
+            token: pending_put.raw_token,
+        };
+        let database = self.database.clone();
+        let _guard = self.lock.lock().await;
+        task::spawn_blocking(move || {
+            let replacing = database.get_pending(&addr_raw)?.is_some();
+            if !replacing && database.count_pendings() as u64 >= SETTINGS.payments.max_queued {
+                return Ok(false);
+            }
+            database.put_pending(&addr_raw, &pending_record)?;
+            Ok(true)
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Apply the pending updates whose POP tokens have enough confirmations.
+    pub async fn process<N: Node>(&self, node: &N, token_cache: &TokenCache) {
+        let database = self.database.clone();
+        let pending = task::spawn_blocking(move || database.get_pendings())
+            .await
+            .unwrap();
+        for (addr_raw, pending_record) in pending {
+            if let Err(err) = self
+                .process_record(node, token_cache, addr_raw, pending_record)
+                .await
+            {
+                error!(message = "failed to update pending queue", error = %err);
+            }
+        }
+    }
+
+    /// Apply a pending update if its POP token has enough confirmations, dropping it if malformed.
+    async fn process_record<N: Node>(
+        &self,
+        node: &N,
+        token_cache: &TokenCache,
+        addr_raw: Vec<u8>,
+        pending_record: PendingRecord,
+    ) -> Result<(), RocksError> {
+        let auth_wrapper_raw = Bytes::from(pending_record.serialized_auth_wrapper.clone());
+        let auth_wrapper = match (
+            pending_record.token.get(..32),
+            AuthWrapper::decode(auth_wrapper_raw.clone()),
+        ) {
+            (Some(tx_id), Ok(auth_wrapper)) => match node.get_confirmations(tx_id).await {
+                Ok(confirmations) if confirmations < SETTINGS.payments.min_confirmations => {
+                    return Ok(())
+                }
+                Ok(_) => Some(auth_wrapper),
+                Err(err) if err.is_not_found() => {
+                    warn!(message = "dropping pending metadata", error = %err);
+                    None
+                }
+                Err(err) => {
+                    warn!(message = "failed to get token confirmations", error = %err);
+                    return Ok(());
+                }
+            },
+            _ => {
+                warn!(message = "dropping malformed pending metadata");
+                None
+            }
+        };
+
+        // Skip the update if it was superseded while processing
+        {
+            let _guard = self.lock.lock().await;
+            if self.database.get_pending(&addr_raw)?.as_ref() != Some(&pending_record) {
+                return Ok(());
+            }
+            self.database.delete_pending(&addr_raw)?;
+        }
+
+        if let Some(auth_wrapper) = auth_wrapper {
+            let addr = Address {
+                body: addr_raw,
+                ..Default::default()
+            };
+            info!(message = "applying pending metadata", address = %addr.encode().unwrap());
+            if let Err(err) = put_metadata(
+                addr,
+                auth_wrapper_raw,
+                auth_wrapper,
+                Authorization::Token(pending_record.token),
+                self.database.clone(),
+                token_cache.clone(),
+            )
+            .await
+            {
+                warn!(message = "failed to apply pending metadata", error = %err);
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,30 @@
+pub mod admin;
+pub mod api_keys;
+pub mod block_notify;
+pub mod info;
+pub mod invoices;
 pub mod metadata;
 pub mod payments;
+pub mod peer_auth;
 pub mod peers;
 pub mod protection;
-
+pub mod rate_limit;
+pub mod sync;
+pub mod tokens;
+
+pub use admin::*;
+pub use api_keys::*;
+pub use block_notify::*;
+pub use info::*;
+pub use invoices::*;
 pub use metadata::*;
 pub use payments::*;
+pub use peer_auth::*;
 pub use peers::*;
 pub use protection::*;
+pub use rate_limit::*;
+pub use sync::*;
+pub use tokens::*;
 
 use std::{convert::Infallible, fmt};
 
@@ -21,6 +39,7 @@ use warp::{
 
 pub const SAMPLING: &str = "Sample-Peers";
 pub const HEADER_VALUE_FALSE: &str = "false";
+pub const HEADER_VALUE_TRUE: &str = "true";
 
 #[derive(Debug, Error)]
 pub struct AddressDecode(
@@ -39,7 +58,7 @@ impl fmt::Display for AddressDecode {
 /// Helper method for decoding an address string.
 pub fn address_decode(addr_str: &str) -> Result<Address, AddressDecode> {
     // Convert address
-    Address::decode(&addr_str).map_err(|(cash_err, base58_err)| AddressDecode(cash_err, base58_err))
+    Address::decode(addr_str).map_err(|(cash_err, base58_err)| AddressDecode(cash_err, base58_err))
 }
 
 impl IntoResponse for AddressDecode {
@@ -54,6 +73,7 @@ pub trait IntoResponse: fmt::Display + Sized {
     fn to_status(&self) -> u16;
 
     /// Convert error into a `Response`.
+    #[allow(clippy::wrong_self_convention)]
     fn into_response(&self) -> Response<Body> {
         let status = self.to_status();
 
@@ -93,6 +113,11 @@ pub async fn handle_rejection(err: Rejection) -> Result<Response<Body>, Infallib
         return Ok(err.into_response());
     }
 
+    if let Some(err) = err.find::<GetPaymentError>() {
+        error!(message = "failed to get payment", error = %err);
+        return Ok(err.into_response());
+    }
+
     if let Some(err) = err.find::<PaymentRequestError>() {
         error!(message = "payment request error", error = %err);
         return Ok(err.into_response());
@@ -103,6 +128,41 @@ pub async fn handle_rejection(err: Rejection) -> Result<Response<Body>, Infallib
         return Ok(err.into_response());
     }
 
+    if let Some(err) = err.find::<ValidateTokenError>() {
+        error!(message = "failed to validate token", error = %err);
+        return Ok(err.into_response());
+    }
+
+    if let Some(err) = err.find::<RateLimited>() {
+        error!(message = "rate limited", error = %err);
+        return Ok(err.into_response());
+    }
+
+    if let Some(err) = err.find::<ApiKeyError>() {
+        error!(message = "api key rejected", error = %err);
+        return Ok(err.into_response());
+    }
+
+    if let Some(err) = err.find::<AnnouncePeerError>() {
+        error!(message = "failed to add peer", error = %err);
+        return Ok(err.into_response());
+    }
+
+    if let Some(err) = err.find::<PeerAuthError>() {
+        error!(message = "peer authentication failed", error = %err);
+        return Ok(err.into_response());
+    }
+
+    if let Some(err) = err.find::<AdminError>() {
+        error!(message = "admin request failed", error = %err);
+        return Ok(err.into_response());
+    }
+
+    if let Some(err) = err.find::<BlockNotifyError>() {
+        error!(message = "block notification rejected", error = %err);
+        return Ok(err.into_response());
+    }
+
     if let Some(err) = err.find::<ProtectionError>() {
         error!(message = "protection triggered", error = %err);
         return Ok(protection_error_recovery(err).await);
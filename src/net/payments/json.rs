@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+pub const PAYMENT_REQUEST_JSON: &str = "application/payment-request";
+pub const PAYMENT_JSON: &str = "application/payment";
+pub const PAYMENT_ACK_JSON: &str = "application/payment-ack";
+
+/// JSON Payment Protocol currency code.
+pub const CURRENCY: &str = "BCH";
+
+/// JSON equivalent of the BIP70 `PaymentDetails`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonPaymentRequest {
+    pub network: String,
+    pub currency: String,
+    pub outputs: Vec<JsonOutput>,
+    pub time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+    pub payment_url: String,
+    pub payment_id: String,
+}
+
+/// JSON equivalent of the BIP70 `Output`, the script is hex encoded.
+#[derive(Debug, Serialize)]
+pub struct JsonOutput {
+    pub amount: u64,
+    pub script: String,
+}
+
+/// JSON equivalent of the BIP70 `Payment`, the transactions are hex encoded.
+#[derive(Debug, Deserialize)]
+pub struct JsonPayment {
+    pub currency: Option<String>,
+    pub transactions: Vec<String>,
+    pub memo: Option<String>,
+}
+
+/// JSON equivalent of the BIP70 `PaymentACK`.
+#[derive(Debug, Serialize)]
+pub struct JsonPaymentAck {
+    pub payment: JsonPaymentAckPayment,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonPaymentAckPayment {
+    pub transactions: Vec<String>,
+}
+
+/// Query string of the JSON payment URL.
+///
+/// The JSON Payment Protocol has no merchant data so the commitment preimage is carried in the
+/// payment ID instead.
+#[derive(Debug, Default, Deserialize)]
+pub struct PaymentQuery {
+    #[serde(rename = "paymentId")]
+    pub payment_id: Option<String>,
+}
+
+/// Convert the configured network to its JSON Payment Protocol name.
+pub fn json_network(network: &str) -> String {
+    match network {
+        "mainnet" => "main".to_string(),
+        "testnet" => "test".to_string(),
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,745 @@
+pub mod json;
+
+use std::{
+    convert::TryInto,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bitcoincash_addr::{cashaddr::EncodingError as AddrEncodingError, Address, HashType};
+use bytes::Bytes;
+use cashweb::{
+    bitcoin::{
+        transaction::{DecodeError as TransactionDecodeError, Transaction},
+        Decodable,
+    },
+    payments::{bip70::*, preprocess_payment, PreprocessingError},
+    token::schemes::chain_commitment::*,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::header::{HeaderMap, HeaderName, ACCEPT, CONTENT_TYPE};
+use lazy_static::lazy_static;
+use prost::Message as _;
+use qrcode::types::QrError;
+use ring::digest::{digest, SHA256};
+use rocksdb::Error as RocksError;
+use thiserror::Error;
+use tokio::task;
+use warp::{
+    http::{
+        header::{AUTHORIZATION, LOCATION},
+        Response,
+    },
+    hyper::Body,
+    reject::Reject,
+};
+
+use super::IntoResponse;
+use crate::{
+    db::Database,
+    models::database::PaymentRecord,
+    node::{tx_id, Node, NodeError},
+    INVOICES_PATH, METADATA_PATH, PAYMENTS_PATH, SETTINGS,
+};
+
+pub use json::*;
+
+pub const COMMITMENT_PREIMAGE_SIZE: usize = 32 + 32;
+pub const COMMITMENT_SIZE: usize = 32;
+pub const MERCHANT_DATA_SIZE: usize = COMMITMENT_PREIMAGE_SIZE + 4;
+pub const OP_RETURN: u8 = 106;
+
+pub const PAYMENT_URI: &str = "Payment-Uri";
+pub const PAYMENT_REQUEST_BIP70: &str = "application/bitcoincash-paymentrequest";
+pub const PAYMENT_ACK_BIP70: &str = "application/bitcoincash-paymentack";
+
+lazy_static! {
+    /// Output script paying the configured payment address.
+    pub static ref FEE_SCRIPT: Option<Vec<u8>> = SETTINGS
+        .payments
+        .address
+        .as_ref()
+        .map(|addr_str| {
+            let address = Address::decode(addr_str).expect("invalid payment address");
+            match address.hash_type {
+                HashType::Key => [&[0x76, 0xa9, 0x14][..], &address.body, &[0x88, 0xac]].concat(),
+                HashType::Script => [&[0xa9, 0x14][..], &address.body, &[0x87]].concat(),
+            }
+        });
+}
+
+/// Payment protocol negotiated with the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaymentFormat {
+    /// BIP70 protobuf messages.
+    Bip70,
+    /// JSON Payment Protocol messages.
+    Json,
+}
+
+/// Check whether a header lists a specific media type.
+fn has_media_type(headers: &HeaderMap, name: HeaderName, media_type: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|item| item.split(';').next())
+        .any(|item| item.trim().eq_ignore_ascii_case(media_type))
+}
+
+impl PaymentFormat {
+    /// Negotiate the format of a payment request using the `Accept` header.
+    pub fn from_request_headers(headers: &HeaderMap) -> Self {
+        if has_media_type(headers, ACCEPT, PAYMENT_REQUEST_JSON) {
+            Self::Json
+        } else {
+            Self::Bip70
+        }
+    }
+
+    /// Negotiate the format of a payment acknowledgement using the `Accept` header.
+    pub fn from_ack_headers(headers: &HeaderMap) -> Self {
+        if has_media_type(headers, ACCEPT, PAYMENT_ACK_JSON) {
+            Self::Json
+        } else {
+            Self::Bip70
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PaymentError {
+    #[error("preprocessing failed: {0}")]
+    Preprocess(PreprocessingError),
+    #[error("failed to decode JSON payment: {0}")]
+    JsonDecode(serde_json::Error),
+    #[error("failed to decode hex: {0}")]
+    HexDecode(hex::FromHexError),
+    #[error("missing commitment")]
+    MissingCommitment,
+    #[error("malformed tx: {0}")]
+    MalformedTx(TransactionDecodeError),
+    #[error("missing merchant data")]
+    MissingMerchantData,
+    #[error("bitcoin request failed: {0}")]
+    Node(NodeError),
+    #[error("transaction {0} rejected by mempool: {1}")]
+    MempoolRejected(String, String),
+    #[error("failed to broadcast transaction {0}: {1}")]
+    Broadcast(String, NodeError),
+    #[error("incorrect length preimage")]
+    IncorrectLengthPreimage,
+    #[error("insufficient payment, paid {0} of {1} satoshis")]
+    InsufficientPayment(u64, u64),
+    #[error("address encoding failed: {0}")]
+    Address(AddrEncodingError),
+    #[error("failed to access database: {0}")]
+    Database(RocksError),
+}
+
+impl Reject for PaymentError {}
+
+impl IntoResponse for PaymentError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::Address(_) => 400,
+            Self::IncorrectLengthPreimage => 400,
+            Self::InsufficientPayment(_, _) => 400,
+            Self::Preprocess(err) => match err {
+                PreprocessingError::MissingAcceptHeader => 406,
+                PreprocessingError::MissingContentTypeHeader => 415,
+                PreprocessingError::PaymentDecode(_) => 400,
+            },
+            Self::JsonDecode(_) => 400,
+            Self::HexDecode(_) => 400,
+            Self::MalformedTx(_) => 400,
+            Self::MissingMerchantData => 400,
+            Self::MissingCommitment => 400,
+            Self::Node(err) => match err {
+                NodeError::Rpc(_, _) => 400,
+                _ => 500,
+            },
+            Self::MempoolRejected(_, _) => 400,
+            Self::Broadcast(_, err) => match err {
+                NodeError::Rpc(_, _) => 400,
+                _ => 500,
+            },
+            Self::Database(_) => 500,
+        }
+    }
+}
+
+/// Extract the commitment from an output script, if it is a commitment output.
+fn extract_commitment(raw_script: &[u8]) -> Option<&[u8]> {
+    if raw_script.len() == 2 + COMMITMENT_SIZE
+        && raw_script[0] == OP_RETURN
+        && raw_script[1] == COMMITMENT_SIZE as u8
+    {
+        Some(&raw_script[2..])
+    } else {
+        None
+    }
+}
+
+/// Construct invoice merchant data, the commitment preimage followed by the wrapper size.
+pub fn construct_merchant_data(pub_key_hash: &[u8], metadata_digest: &[u8], size: u32) -> Vec<u8> {
+    [pub_key_hash, metadata_digest, &size.to_le_bytes()].concat()
+}
+
+/// Split invoice merchant data into the commitment preimage and the wrapper size.
+pub fn split_merchant_data(merchant_data: &[u8]) -> Option<(&[u8], u32)> {
+    if merchant_data.len() != MERCHANT_DATA_SIZE {
+        return None;
+    }
+    let (commitment_preimage, raw_size) = merchant_data.split_at(COMMITMENT_PREIMAGE_SIZE);
+    let size = u32::from_le_bytes(raw_size.try_into().unwrap()); // This is safe
+    Some((commitment_preimage, size))
+}
+
+/// Price, in satoshis, of storing a wrapper of the given size.
+pub fn price(size: u32) -> u64 {
+    SETTINGS.payments.pricing.price(size as u64)
+}
+
+/// Construct a `PaymentACK` response from a `PaymentRecord`.
+fn payment_ack_response(payment_record: PaymentRecord, format: PaymentFormat) -> Response<Body> {
+    let builder = Response::builder()
+        .header(
+            LOCATION,
+            format!("/{}/{}", METADATA_PATH, payment_record.address),
+        )
+        .header(AUTHORIZATION, payment_record.token);
+
+    match format {
+        PaymentFormat::Bip70 => builder
+            .header(CONTENT_TYPE, PAYMENT_ACK_BIP70)
+            .body(Body::from(payment_record.serialized_payment_ack))
+            .unwrap(),
+        PaymentFormat::Json => {
+            let payment_ack =
+                PaymentAck::decode(&payment_record.serialized_payment_ack[..]).unwrap(); // This is safe
+            let json_payment_ack = JsonPaymentAck {
+                payment: JsonPaymentAckPayment {
+                    transactions: payment_ack
+                        .payment
+                        .transactions
+                        .iter()
+                        .map(hex::encode)
+                        .collect(),
+                },
+                memo: payment_ack.memo,
+            };
+            builder
+                .header(CONTENT_TYPE, PAYMENT_ACK_JSON)
+                .body(Body::from(serde_json::to_vec(&json_payment_ack).unwrap()))
+                .unwrap()
+        }
+    }
+}
+
+/// Parse a payment in either format, negotiated using the `Content-Type` header.
+pub async fn parse_payment(
+    headers: HeaderMap,
+    query: PaymentQuery,
+    body: Bytes,
+) -> Result<(Payment, PaymentFormat), PaymentError> {
+    if !has_media_type(&headers, CONTENT_TYPE, PAYMENT_JSON) {
+        let payment = preprocess_payment(headers, body)
+            .await
+            .map_err(PaymentError::Preprocess)?;
+        return Ok((payment, PaymentFormat::Bip70));
+    }
+
+    let json_payment: JsonPayment =
+        serde_json::from_slice(&body).map_err(PaymentError::JsonDecode)?;
+    let merchant_data = query
+        .payment_id
+        .ok_or(PaymentError::MissingMerchantData)
+        .and_then(|payment_id| hex::decode(payment_id).map_err(PaymentError::HexDecode))?;
+    let transactions = json_payment
+        .transactions
+        .iter()
+        .map(hex::decode)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(PaymentError::HexDecode)?;
+    let payment = Payment {
+        merchant_data: Some(merchant_data),
+        transactions,
+        refund_to: Vec::new(),
+        memo: json_payment.memo,
+    };
+    Ok((payment, PaymentFormat::Json))
+}
+
+pub async fn process_payment<N: Node>(
+    payment: Payment,
+    format: PaymentFormat,
+    node: N,
+    database: Database,
+) -> Result<Response<Body>, PaymentError> {
+    // Deserialize transactions
+    let txs_res: Result<Vec<(Transaction, Vec<u8>)>, _> = payment
+        .transactions
+        .iter()
+        .map(|raw_tx| {
+            let tx_id = tx_id(raw_tx);
+            Transaction::decode(&mut raw_tx.as_slice()).map(move |tx| (tx, tx_id))
+        })
+        .collect();
+    let txs = txs_res.map_err(PaymentError::MalformedTx)?;
+
+    // Find commitment output
+    let merchant_data = payment
+        .merchant_data
+        .as_ref()
+        .ok_or(PaymentError::MissingMerchantData)?;
+
+    let (commitment_preimage, size) =
+        split_merchant_data(merchant_data).ok_or(PaymentError::IncorrectLengthPreimage)?;
+
+    // Get address
+    let pub_key_hash = &commitment_preimage[..32];
+    let address = Address {
+        body: pub_key_hash.to_vec(),
+        ..Default::default()
+    };
+    let addr_str = address.encode().map_err(PaymentError::Address)?;
+
+    // Extract metadata
+    let address_metadata_hash = &commitment_preimage[32..COMMITMENT_PREIMAGE_SIZE];
+
+    let expected_commitment = construct_commitment(pub_key_hash, address_metadata_hash);
+
+    let (commitment_tx, tx_id, vout) = txs
+        .iter()
+        .find_map(|(tx, tx_id)| {
+            tx.outputs
+                .iter()
+                .enumerate()
+                .find_map(|(vout, output)| {
+                    let commitment = extract_commitment(output.script.as_bytes())?;
+                    if commitment == &expected_commitment[..] {
+                        Some(vout)
+                    } else {
+                        None
+                    }
+                })
+                .map(|vout| (tx, tx_id, vout))
+        })
+        .ok_or(PaymentError::MissingCommitment)?;
+
+    // If already processed then respond with the original acknowledgement
+    if let Some(payment_record) = database
+        .get_payment(tx_id)
+        .map_err(PaymentError::Database)?
+    {
+        return Ok(payment_ack_response(payment_record, format));
+    }
+
+    // Check the fee paid covers the price of the invoiced wrapper. Tokens are priced against
+    // the actual wrapper size on validation, so the fee must be paid by the commitment transaction.
+    let required = price(size);
+    if required > 0 {
+        let paid = FEE_SCRIPT
+            .as_ref()
+            .map(|fee_script| {
+                commitment_tx
+                    .outputs
+                    .iter()
+                    .filter(|output| output.script.as_bytes() == &fee_script[..])
+                    .fold(0u64, |total, output| total.saturating_add(output.value))
+            })
+            .unwrap_or_default();
+        if paid < required {
+            return Err(PaymentError::InsufficientPayment(paid, required));
+        }
+    }
+
+    // Broadcast parents before the transactions spending them. Transactions spending other
+    // transactions of the payment can't be evaluated until their parents are in the mempool, so
+    // only the others are checked against the mempool before broadcasting any of them.
+    let parents = payment_parents(&txs);
+    let order = dependency_order(&parents);
+    for &index in order.iter().filter(|&&index| parents[index].is_empty()) {
+        test_mempool_accept(&node, &payment.transactions[index], &txs[index].1).await?;
+    }
+
+    // Broadcast transactions
+    for &index in &order {
+        let (raw_tx, tx_id) = (&payment.transactions[index], &txs[index].1);
+        if !parents[index].is_empty() {
+            test_mempool_accept(&node, raw_tx, tx_id).await?;
+        }
+        node.send_tx(raw_tx)
+            .await
+            .map_err(|err| PaymentError::Broadcast(hex::encode(tx_id), err))?;
+    }
+
+    // Construct token
+    let token = format!("POP {}", construct_token(tx_id, vout as u32));
+
+    // Create PaymentAck
+    let memo = Some(SETTINGS.payments.memo.clone());
+    let payment_ack = PaymentAck { payment, memo };
+
+    // Encode payment ack
+    let mut raw_ack = Vec::with_capacity(payment_ack.encoded_len());
+    payment_ack.encode(&mut raw_ack).unwrap();
+
+    // Record payment
+    let payment_record = PaymentRecord {
+        serialized_payment_ack: raw_ack,
+        token,
+        address: addr_str,
+        size,
+    };
+    let mut raw_payment_record = Vec::with_capacity(payment_record.encoded_len());
+    payment_record.encode(&mut raw_payment_record).unwrap(); // This is safe
+    let tx_id = tx_id.clone();
+    task::spawn_blocking(move || database.put_payment(&tx_id, &raw_payment_record))
+        .await
+        .unwrap()
+        .map_err(PaymentError::Database)?;
+
+    Ok(payment_ack_response(payment_record, format))
+}
+
+/// Check a transaction would be accepted to the mempool.
+async fn test_mempool_accept<N: Node>(
+    node: &N,
+    raw_tx: &[u8],
+    tx_id: &[u8],
+) -> Result<(), PaymentError> {
+    let acceptance = node
+        .test_mempool_accept(raw_tx)
+        .await
+        .map_err(PaymentError::Node)?;
+    if !acceptance.allowed {
+        return Err(PaymentError::MempoolRejected(
+            hex::encode(tx_id),
+            acceptance.reject_reason.unwrap_or_default(),
+        ));
+    }
+    Ok(())
+}
+
+/// Indices of the transactions of a payment spent by each of its transactions.
+fn payment_parents(txs: &[(Transaction, Vec<u8>)]) -> Vec<Vec<usize>> {
+    txs.iter()
+        .map(|(tx, _)| {
+            (0..txs.len())
+                .filter(|&parent| {
+                    let parent_id = &txs[parent].1;
+                    tx.inputs
+                        .iter()
+                        .any(|input| input.outpoint.tx_id.iter().rev().eq(parent_id.iter()))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Order transactions so each follows the transactions it spends, preserving the given order
+/// otherwise.
+fn dependency_order(parents: &[Vec<usize>]) -> Vec<usize> {
+    let mut order = Vec::with_capacity(parents.len());
+    let mut remaining: Vec<usize> = (0..parents.len()).collect();
+    while !remaining.is_empty() {
+        let (ready, blocked): (Vec<usize>, Vec<usize>) = remaining.iter().partition(|&&index| {
+            parents[index]
+                .iter()
+                .all(|parent| !remaining.contains(parent))
+        });
+
+        // Transactions can't spend each other, cycles are left for the node to reject
+        if ready.is_empty() {
+            order.extend(blocked);
+            break;
+        }
+        order.extend(ready);
+        remaining = blocked;
+    }
+    order
+}
+
+#[derive(Debug, Error)]
+pub enum GetPaymentError {
+    #[error("failed to decode transaction ID: {0}")]
+    TxIdDecode(hex::FromHexError),
+    #[error("incorrect length transaction ID")]
+    IncorrectLengthTxId,
+    #[error("not found")]
+    NotFound,
+    #[error("malformed tx: {0}")]
+    MalformedTx(TransactionDecodeError),
+    #[error("missing commitment")]
+    MissingCommitment,
+    #[error("bitcoin request failed: {0}")]
+    Node(NodeError),
+    #[error("failed to read from database: {0}")]
+    Database(RocksError),
+}
+
+impl Reject for GetPaymentError {}
+
+impl IntoResponse for GetPaymentError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::TxIdDecode(_) => 400,
+            Self::IncorrectLengthTxId => 400,
+            Self::NotFound => 404,
+            Self::MalformedTx(_) => 500,
+            Self::MissingCommitment => 404,
+            Self::Node(_) => 500,
+            Self::Database(_) => 500,
+        }
+    }
+}
+
+/// Handles payment GET requests, allowing clients to recover a lost `PaymentACK` or POP token.
+pub async fn get_payment<N: Node>(
+    tx_id_hex: String,
+    headers: HeaderMap,
+    node: N,
+    database: Database,
+) -> Result<Response<Body>, GetPaymentError> {
+    let tx_id = hex::decode(tx_id_hex).map_err(GetPaymentError::TxIdDecode)?;
+    if tx_id.len() != 32 {
+        return Err(GetPaymentError::IncorrectLengthTxId);
+    }
+
+    // If processed by this server then respond with the original acknowledgement
+    if let Some(payment_record) = database
+        .get_payment(&tx_id)
+        .map_err(GetPaymentError::Database)?
+    {
+        let format = PaymentFormat::from_ack_headers(&headers);
+        return Ok(payment_ack_response(payment_record, format));
+    }
+
+    // Otherwise rebuild the token from the commitment transaction
+    let raw_tx = node
+        .get_raw_transaction(&tx_id)
+        .await
+        .map_err(|err| match err {
+            NodeError::Rpc(_, _) => GetPaymentError::NotFound,
+            err => GetPaymentError::Node(err),
+        })?;
+    let tx = Transaction::decode(&mut raw_tx.as_slice()).map_err(GetPaymentError::MalformedTx)?;
+    let vout = tx
+        .outputs
+        .iter()
+        .position(|output| extract_commitment(output.script.as_bytes()).is_some())
+        .ok_or(GetPaymentError::MissingCommitment)?;
+    let token = format!("POP {}", construct_token(&tx_id, vout as u32));
+
+    Ok(Response::builder()
+        .header(AUTHORIZATION, token)
+        .body(Body::empty())
+        .unwrap())
+}
+
+#[derive(Debug, Error)]
+pub enum PaymentRequestError {
+    #[error("failed to decode invoice ID: {0}")]
+    InvoiceIdDecode(hex::FromHexError),
+    #[error("incorrect length preimage")]
+    IncorrectLengthPreimage,
+    #[error("failed to render QR code: {0}")]
+    QrCode(QrError),
+    #[error("bitcoin request failed: {0}")]
+    Node(NodeError),
+    #[error("unexpected network")]
+    UnepxectedNetwork,
+}
+
+impl Reject for PaymentRequestError {}
+
+impl IntoResponse for PaymentRequestError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::InvoiceIdDecode(_) => 400,
+            Self::IncorrectLengthPreimage => 400,
+            Self::QrCode(_) => 500,
+            Self::Node(err) => match err {
+                NodeError::Rpc(_, _) => 400,
+                _ => 500,
+            },
+            Self::UnepxectedNetwork => 400,
+        }
+    }
+}
+
+/// Construct an absolute URL from a path, using the configured public URL.
+pub fn public_url(path: &str) -> String {
+    format!("{}{}", SETTINGS.public_url.trim_end_matches('/'), path)
+}
+
+/// Construct a BIP21 URI pointing at the invoice with the given merchant data.
+pub fn payment_uri(merchant_data: &[u8]) -> String {
+    let invoice_url = public_url(&format!(
+        "/{}/{}",
+        INVOICES_PATH,
+        hex::encode(merchant_data)
+    ));
+    format!("bitcoincash:?r={}", invoice_url)
+}
+
+/// Construct a payment request for the given merchant data, returning its content type and body.
+///
+/// The merchant data must have been checked using `split_merchant_data`.
+pub fn construct_payment_request(
+    merchant_data: Vec<u8>,
+    format: PaymentFormat,
+) -> (&'static str, Vec<u8>) {
+    let (commitment_preimage, size) = split_merchant_data(&merchant_data).unwrap();
+
+    // Construct metadata commitment
+    let commitment = digest(&SHA256, commitment_preimage);
+    let op_return_pre: [u8; 2] = [106, COMMITMENT_SIZE as u8];
+    let script = [&op_return_pre[..], commitment.as_ref()].concat();
+
+    // Construct outputs, charging for the wrapper if a payment address is configured
+    let mut outputs = vec![(0, script)];
+    let amount = price(size);
+    if let Some(fee_script) = FEE_SCRIPT.as_ref().filter(|_| amount > 0) {
+        outputs.push((amount, fee_script.clone()));
+    }
+
+    // Valid interval
+    let current_time = SystemTime::now();
+
+    match format {
+        PaymentFormat::Bip70 => {
+            let outputs = outputs
+                .into_iter()
+                .map(|(amount, script)| Output {
+                    amount: Some(amount).filter(|amount| *amount > 0),
+                    script,
+                })
+                .collect();
+            let payment_details = PaymentDetails {
+                network: Some(SETTINGS.network.to_string()),
+                time: current_time.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                expires: None,
+                memo: None,
+                merchant_data: Some(merchant_data),
+                outputs,
+                payment_url: Some(public_url(&format!("/{}", PAYMENTS_PATH))),
+            };
+            let mut serialized_payment_details = Vec::with_capacity(payment_details.encoded_len());
+            payment_details
+                .encode(&mut serialized_payment_details)
+                .unwrap();
+
+            // Generate payment invoice
+            // TODO: Signing
+            let pki_type = Some("none".to_string());
+            let payment_invoice = PaymentRequest {
+                pki_type,
+                pki_data: None,
+                payment_details_version: Some(1),
+                serialized_payment_details,
+                signature: None,
+            };
+            let mut payment_invoice_raw = Vec::with_capacity(payment_invoice.encoded_len());
+            payment_invoice.encode(&mut payment_invoice_raw).unwrap();
+
+            (PAYMENT_REQUEST_BIP70, payment_invoice_raw)
+        }
+        PaymentFormat::Json => {
+            let outputs = outputs
+                .into_iter()
+                .map(|(amount, script)| JsonOutput {
+                    amount,
+                    script: hex::encode(script),
+                })
+                .collect();
+            let payment_id = hex::encode(&merchant_data);
+            let time =
+                DateTime::<Utc>::from(current_time).to_rfc3339_opts(SecondsFormat::Secs, true);
+            let payment_request = JsonPaymentRequest {
+                network: json_network(&SETTINGS.network),
+                currency: CURRENCY.to_string(),
+                outputs,
+                time,
+                expires: None,
+                memo: None,
+                payment_url: public_url(&format!("/{}?paymentId={}", PAYMENTS_PATH, payment_id)),
+                payment_id,
+            };
+
+            (
+                PAYMENT_REQUEST_JSON,
+                serde_json::to_vec(&payment_request).unwrap(),
+            )
+        }
+    }
+}
+
+pub fn construct_payment_response(
+    pub_key_hash: &[u8],
+    metadata_digest: &[u8],
+    size: u32,
+    format: PaymentFormat,
+) -> Response<Body> {
+    let merchant_data = construct_merchant_data(pub_key_hash, metadata_digest, size);
+    let payment_uri = payment_uri(&merchant_data);
+    let (content_type, payment_request) = construct_payment_request(merchant_data, format);
+
+    Response::builder()
+        .status(402)
+        .header(CONTENT_TYPE, content_type)
+        .header(PAYMENT_URI, payment_uri)
+        .body(Body::from(payment_request))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cashweb::bitcoin::{
+        transaction::{outpoint::Outpoint, Input, Script},
+        Encodable,
+    };
+
+    fn transaction(spent_tx_id: [u8; 32]) -> (Transaction, Vec<u8>) {
+        let tx = Transaction {
+            version: 1,
+            inputs: vec![Input {
+                outpoint: Outpoint {
+                    tx_id: spent_tx_id,
+                    vout: 0,
+                },
+                script: Script(vec![]),
+                sequence: 0xffff_ffff,
+            }],
+            outputs: vec![],
+            lock_time: 0,
+        };
+        let mut raw_tx = Vec::with_capacity(tx.encoded_len());
+        tx.encode(&mut raw_tx).unwrap();
+        let tx_id = tx_id(&raw_tx);
+        (tx, tx_id)
+    }
+
+    #[test]
+    fn dependencies() {
+        // Outpoints reference transaction IDs in reverse byte order
+        let parent = transaction([1; 32]);
+        let mut parent_id = [0; 32];
+        parent_id.copy_from_slice(&parent.1);
+        parent_id.reverse();
+        let child = transaction(parent_id);
+        assert_eq!(payment_parents(&[child, parent]), vec![vec![1], vec![]]);
+
+        // The second transaction spends the third, which spends the first
+        let parents = vec![vec![], vec![2], vec![0], vec![]];
+        assert_eq!(dependency_order(&parents), vec![0, 3, 2, 1]);
+
+        // Cycles are kept rather than dropped
+        let parents = vec![vec![1], vec![0], vec![]];
+        assert_eq!(dependency_order(&parents), vec![2, 0, 1]);
+    }
+}
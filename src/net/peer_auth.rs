@@ -0,0 +1,115 @@
+use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+
+use thiserror::Error;
+use warp::{reject::Reject, Filter, Rejection};
+
+use super::IntoResponse;
+use crate::{
+    peering::{
+        certificate_fingerprint, load_certificate, parse_fingerprint, Fingerprint, TlsConfigError,
+    },
+    SETTINGS,
+};
+
+#[derive(Debug, Error)]
+pub enum PeerAuthError {
+    #[error("client certificate required")]
+    MissingCertificate,
+    #[error("invalid client certificate fingerprint")]
+    Fingerprint,
+    #[error("client certificate not accepted")]
+    UnknownCertificate,
+}
+
+impl Reject for PeerAuthError {}
+
+impl IntoResponse for PeerAuthError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::MissingCertificate => 401,
+            Self::Fingerprint => 400,
+            Self::UnknownCertificate => 403,
+        }
+    }
+}
+
+/// Fingerprints of the client certificates accepted on peer-only routes, those of pinned peers
+/// and those listed explicitly.
+pub fn accepted_fingerprints() -> Result<HashSet<Fingerprint>, TlsConfigError> {
+    let tls = &SETTINGS.peering.tls;
+    let mut accepted = HashSet::new();
+    for pinned_peer in &tls.pinned_peers {
+        let certificate = load_certificate(&pinned_peer.certificate)?;
+        accepted.insert(certificate_fingerprint(&certificate)?);
+    }
+    for fingerprint in &tls.client_fingerprints {
+        accepted.insert(parse_fingerprint(fingerprint).map_err(TlsConfigError::Fingerprint)?);
+    }
+    Ok(accepted)
+}
+
+/// Check a client certificate fingerprint is accepted.
+pub fn check_client_certificate(
+    fingerprint: Option<&str>,
+    accepted: &HashSet<Fingerprint>,
+) -> Result<(), PeerAuthError> {
+    let fingerprint = fingerprint.ok_or(PeerAuthError::MissingCertificate)?;
+    let fingerprint = parse_fingerprint(fingerprint).map_err(|_| PeerAuthError::Fingerprint)?;
+    if !accepted.contains(&fingerprint) {
+        return Err(PeerAuthError::UnknownCertificate);
+    }
+    Ok(())
+}
+
+/// Filter rejecting requests without an accepted client certificate, if required.
+///
+/// Certificates are verified by a TLS terminating proxy, the fingerprint header is only trusted
+/// when sent by one of the trusted proxies.
+pub fn peer_auth(
+    accepted: Arc<HashSet<Fingerprint>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let tls = &SETTINGS.peering.tls;
+    warp::addr::remote()
+        .and(warp::header::optional::<String>(&tls.client_cert_header))
+        .and_then(
+            move |remote: Option<SocketAddr>, fingerprint: Option<String>| {
+                let accepted = accepted.clone();
+                async move {
+                    if !tls.require_client_cert {
+                        return Ok(());
+                    }
+                    let trusted = remote
+                        .map(|addr| SETTINGS.rate_limits.trusted_proxies.contains(&addr.ip()))
+                        .unwrap_or_default();
+                    let fingerprint = fingerprint.as_deref().filter(|_| trusted);
+                    check_client_certificate(fingerprint, &accepted).map_err(warp::reject::custom)
+                }
+            },
+        )
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_certificates() {
+        let partner = [0x42; 32];
+        let accepted: HashSet<Fingerprint> = vec![partner].into_iter().collect();
+
+        assert!(check_client_certificate(Some(&hex::encode(partner)), &accepted).is_ok());
+        assert!(matches!(
+            check_client_certificate(None, &accepted),
+            Err(PeerAuthError::MissingCertificate)
+        ));
+        assert!(matches!(
+            check_client_certificate(Some("not hex"), &accepted),
+            Err(PeerAuthError::Fingerprint)
+        ));
+        assert!(matches!(
+            check_client_certificate(Some(&hex::encode([0x43; 32])), &accepted),
+            Err(PeerAuthError::UnknownCertificate)
+        ));
+    }
+}
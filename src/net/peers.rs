@@ -1,8 +1,38 @@
+use std::{
+    fmt,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use hyper::{http::uri::InvalidUri, Request, Uri};
+use lazy_static::lazy_static;
+use prost::Message as _;
 use thiserror::Error;
+use tower_service::Service;
+use tracing::info;
 use warp::{http::Response, hyper::Body, reject::Reject};
 
 use super::IntoResponse;
-use crate::{peering::PeerHandler, SETTINGS};
+use crate::{models::keyserver::Peer, peering::PeerHandler, SETTINGS};
+
+/// Maximum size of a peer announcement.
+pub const ANNOUNCEMENT_LIMIT: u64 = 1_000;
+
+/// Maximum number of peers a single source may announce per window.
+const MAX_ANNOUNCEMENTS_PER_SOURCE: u32 = 8;
+
+/// Window over which the announcements of a source are counted.
+const ANNOUNCEMENT_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Number of sources tracked before those with expired windows are pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+lazy_static! {
+    // Start of the current window and number of peers probed within it, by source
+    static ref ANNOUNCEMENTS: DashMap<IpAddr, (Instant, u32)> = DashMap::new();
+}
 
 #[derive(Debug, Error)]
 #[error("peering not supported")]
@@ -26,3 +56,112 @@ pub async fn get_peers<S: Clone>(
     let raw_peers = peer_handler.get_raw_peers().await;
     Ok(Response::builder().body(Body::from(raw_peers)).unwrap()) // TODO: Headers
 }
+
+#[derive(Debug, Error)]
+pub enum AnnouncePeerError {
+    #[error("peering not supported")]
+    PeeringUnavailible,
+    #[error("failed to decode peer: {0}")]
+    Decode(prost::DecodeError),
+    #[error("invalid peer url: {0}")]
+    Uri(InvalidUri),
+    #[error("peer is this server")]
+    SelfAnnouncement,
+    #[error("failed to probe peer: {0}")]
+    Probe(String),
+    #[error("too many peers announced from this source")]
+    SourceLimit,
+}
+
+impl Reject for AnnouncePeerError {}
+
+impl IntoResponse for AnnouncePeerError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::PeeringUnavailible => 501,
+            Self::SourceLimit => 429,
+            _ => 400,
+        }
+    }
+}
+
+/// Handles peer announcements, adding the peer once probed.
+pub async fn post_peer<S>(
+    body: Bytes,
+    source: IpAddr,
+    peer_handler: PeerHandler<S>,
+) -> Result<Response<Body>, AnnouncePeerError>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S: Send + Clone + 'static,
+    S::Future: Send,
+    S::Error: fmt::Debug + Send + fmt::Display,
+{
+    if !SETTINGS.peering.enabled {
+        return Err(AnnouncePeerError::PeeringUnavailible);
+    }
+
+    let peer = Peer::decode(body).map_err(AnnouncePeerError::Decode)?;
+    if peer.url.trim_end_matches('/') == SETTINGS.public_url.trim_end_matches('/') {
+        return Err(AnnouncePeerError::SelfAnnouncement);
+    }
+    let uri: Uri = peer.url.parse().map_err(AnnouncePeerError::Uri)?;
+
+    // Skip known peers
+    if peer_handler.get_urls().await.contains(&uri) {
+        return Ok(Response::builder().body(Body::empty()).unwrap());
+    }
+
+    // Check the source hasn't flooded the peer list, counting each probe it causes
+    count_announcement(source, Instant::now())?;
+
+    peer_handler
+        .probe(&uri)
+        .await
+        .map_err(|err| AnnouncePeerError::Probe(err.to_string()))?;
+    if peer_handler.add_peer(uri).await {
+        info!(message = "added announced peer", peer = %peer.url, source = %source);
+    }
+
+    Ok(Response::builder().body(Body::empty()).unwrap())
+}
+
+/// Count an announcement from a source, refusing it once the source exhausted its window.
+fn count_announcement(source: IpAddr, now: Instant) -> Result<(), AnnouncePeerError> {
+    if ANNOUNCEMENTS.len() > PRUNE_THRESHOLD {
+        ANNOUNCEMENTS.retain(|_, (start, _)| now.duration_since(*start) < ANNOUNCEMENT_WINDOW);
+    }
+
+    let mut announcements = ANNOUNCEMENTS.entry(source).or_insert((now, 0));
+    if now.duration_since(announcements.0) >= ANNOUNCEMENT_WINDOW {
+        *announcements = (now, 0);
+    }
+    if announcements.1 >= MAX_ANNOUNCEMENTS_PER_SOURCE {
+        return Err(AnnouncePeerError::SourceLimit);
+    }
+    announcements.1 += 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn announcement_window() {
+        let source = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let start = Instant::now();
+        for _ in 0..MAX_ANNOUNCEMENTS_PER_SOURCE {
+            assert!(count_announcement(source, start).is_ok());
+        }
+        assert!(matches!(
+            count_announcement(source, start),
+            Err(AnnouncePeerError::SourceLimit)
+        ));
+
+        // The count resets once the window has passed
+        assert!(count_announcement(source, start + ANNOUNCEMENT_WINDOW).is_ok());
+    }
+}
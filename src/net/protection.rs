@@ -2,26 +2,62 @@ use std::sync::Arc;
 
 use bitcoincash_addr::Address;
 use bytes::Bytes;
-use cashweb::bitcoin_client::HttpClient;
-use cashweb::token::{extract_pop, schemes::chain_commitment::*};
+use cashweb::token::extract_pop;
 use http::header::HeaderMap;
-use hyper::Error as HyperError;
 use prost::Message as _;
 use ring::digest::{digest, SHA256};
+use rocksdb::Error as RocksError;
 use thiserror::Error;
 use tracing::info;
 use warp::{http::Response, hyper::Body, reject::Reject};
 
-use crate::{models::wrapper::AuthWrapper, net::payments};
+use crate::{
+    models::wrapper::AuthWrapper,
+    net::{
+        api_keys, payments, verify_auth_wrapper, IntoResponse, PendingPut, PendingQueue,
+        PutMetadataError,
+    },
+    node::{Node, NodeError},
+    settings::{ApiKey, ApiKeyScope, TokenSchemeKind},
+    token::{TokenScheme, ValidationError},
+    SETTINGS,
+};
+
+pub const CONFIRMATIONS_REMAINING: &str = "Confirmations-Remaining";
+
+/// Proof authorizing a metadata update.
+#[derive(Debug)]
+pub enum Authorization {
+    /// Raw POP token.
+    Token(Vec<u8>),
+    /// Operator API key.
+    ApiKey(&'static ApiKey),
+}
 
 #[derive(Debug, Error)]
 pub enum ProtectionError {
     #[error("missing token, pubkey: {0:?}")] // TODO: Make this prettier
-    MissingToken(Vec<u8>, Vec<u8>),
+    MissingToken(Vec<u8>, Vec<u8>, u32, payments::PaymentFormat),
+    #[error("missing token")]
+    Unauthorized,
+    #[error(transparent)]
+    ApiKey(api_keys::ApiKeyError),
+    #[error("token paid for a smaller wrapper, pubkey: {0:?}")]
+    Underpaid(Vec<u8>, Vec<u8>, u32, payments::PaymentFormat),
     #[error("validation failed: {0}")]
-    Validation(ValidationError<HyperError>),
+    Validation(ValidationError),
     #[error("failed to decode authorization wrapper: {0}")]
     Decode(prost::DecodeError),
+    #[error("failed to get token confirmations: {0}")]
+    Node(NodeError),
+    #[error("token requires {0} more confirmations")]
+    Unconfirmed(u32),
+    #[error("queued until token has {0} more confirmations")]
+    Queued(u32),
+    #[error("failed to queue metadata: {0}")]
+    Database(RocksError),
+    #[error(transparent)]
+    Verify(PutMetadataError),
 }
 
 pub async fn protection_error_recovery(err: &ProtectionError) -> Response<Body> {
@@ -30,27 +66,67 @@ pub async fn protection_error_recovery(err: &ProtectionError) -> Response<Body>
             .status(400)
             .body(Body::from(err.to_string()))
             .unwrap(),
-        ProtectionError::MissingToken(pubkey_digest, metadata_digest) => {
-            payments::construct_payment_response(pubkey_digest, metadata_digest)
+        ProtectionError::MissingToken(pubkey_digest, metadata_digest, size, format)
+        | ProtectionError::Underpaid(pubkey_digest, metadata_digest, size, format) => {
+            payments::construct_payment_response(pubkey_digest, metadata_digest, *size, *format)
         }
+        ProtectionError::Unauthorized => Response::builder()
+            .status(401)
+            .body(Body::from(err.to_string()))
+            .unwrap(),
+        ProtectionError::ApiKey(err) => err.into_response(),
+        ProtectionError::Verify(err) => err.into_response(),
         ProtectionError::Decode(err) => Response::builder()
             .status(400)
             .body(Body::from(err.to_string()))
             .unwrap(),
+        ProtectionError::Node(err) => Response::builder()
+            .status(500)
+            .body(Body::from(err.to_string()))
+            .unwrap(),
+        ProtectionError::Database(err) => Response::builder()
+            .status(500)
+            .body(Body::from(err.to_string()))
+            .unwrap(),
+        ProtectionError::Unconfirmed(remaining) => Response::builder()
+            .status(425)
+            .header(CONFIRMATIONS_REMAINING, *remaining)
+            .body(Body::from(err.to_string()))
+            .unwrap(),
+        ProtectionError::Queued(remaining) => Response::builder()
+            .status(202)
+            .header(CONFIRMATIONS_REMAINING, *remaining)
+            .body(Body::from(err.to_string()))
+            .unwrap(),
     }
 }
 
 impl Reject for ProtectionError {}
 
-pub async fn pop_protection(
+pub async fn pop_protection<N: Node>(
     addr: Address,
     auth_wrapper_raw: Bytes,
     header_map: HeaderMap,
-    token_scheme: Arc<ChainCommitmentScheme<HttpClient>>,
-) -> Result<(Address, Bytes, AuthWrapper, Vec<u8>), ProtectionError> {
+    token_scheme: Arc<dyn TokenScheme>,
+    node: N,
+    pending_queue: PendingQueue,
+) -> Result<(Address, Bytes, AuthWrapper, Authorization), ProtectionError> {
     let auth_wrapper =
         AuthWrapper::decode(auth_wrapper_raw.clone()).map_err(ProtectionError::Decode)?;
 
+    // Operator API keys bypass the token requirement
+    if let Some(key) = header_map
+        .get(api_keys::API_KEY)
+        .and_then(|value| value.to_str().ok())
+    {
+        let api_key = api_keys::authorize_api_key(key, ApiKeyScope::Metadata)
+            .map_err(ProtectionError::ApiKey)?;
+        api_keys::reserve_quota(api_key).map_err(ProtectionError::ApiKey)?;
+        info!(message = "found api key", name = %api_key.name);
+        let authorization = Authorization::ApiKey(api_key);
+        return Ok((addr, auth_wrapper_raw, auth_wrapper, authorization));
+    }
+
     let metadata_hash = if auth_wrapper.payload_digest.len() == 32 {
         auth_wrapper.payload_digest.clone()
     } else {
@@ -60,18 +136,86 @@ pub async fn pop_protection(
     // SHA256 of the public key
     let pub_key_hash = digest(&SHA256, &auth_wrapper.public_key);
 
+    let size = auth_wrapper_raw.len() as u32;
+
     match extract_pop(&header_map) {
         Some(pop_token) => {
             info!(message = "found token", token = %pop_token);
-            let raw_token = token_scheme
-                .validate_token(pub_key_hash.as_ref(), &metadata_hash, pop_token)
+            let raw_token = match token_scheme
+                .validate_token(
+                    pub_key_hash.as_ref(),
+                    &metadata_hash,
+                    pop_token,
+                    auth_wrapper_raw.len(),
+                )
                 .await
-                .map_err(ProtectionError::Validation)?;
-            Ok((addr, auth_wrapper_raw, auth_wrapper, raw_token))
+            {
+                Ok(some) => some,
+                // Offer a payment request for the larger wrapper
+                Err(ValidationError::Underpaid(..)) => {
+                    return Err(ProtectionError::Underpaid(
+                        pub_key_hash.as_ref().to_vec(),
+                        metadata_hash,
+                        size,
+                        payments::PaymentFormat::from_request_headers(&header_map),
+                    ))
+                }
+                Err(err) => return Err(ProtectionError::Validation(err)),
+            };
+
+            // Confirmation checks only apply to tokens committing to a transaction
+            let tx_id = match token_scheme.commitment_tx_id(&raw_token) {
+                Some(some) => some.to_vec(),
+                None => {
+                    let authorization = Authorization::Token(raw_token);
+                    return Ok((addr, auth_wrapper_raw, auth_wrapper, authorization));
+                }
+            };
+
+            // Check the commitment transaction has enough confirmations
+            let min_confirmations = SETTINGS.payments.min_confirmations;
+            if min_confirmations > 0 {
+                let confirmations = node
+                    .get_confirmations(&tx_id)
+                    .await
+                    .map_err(ProtectionError::Node)?;
+                let remaining = min_confirmations.saturating_sub(confirmations);
+                if remaining > 0 {
+                    if !SETTINGS.payments.queue_unconfirmed {
+                        return Err(ProtectionError::Unconfirmed(remaining));
+                    }
+                    // Verify signatures now, rather than accepting an update dropped later
+                    verify_auth_wrapper(auth_wrapper.clone()).map_err(ProtectionError::Verify)?;
+                    let pending_put = PendingPut {
+                        addr,
+                        auth_wrapper_raw,
+                        auth_wrapper,
+                        raw_token,
+                    };
+                    // Refuse the update as unconfirmed once the queue is full
+                    if !pending_queue
+                        .add(pending_put)
+                        .await
+                        .map_err(ProtectionError::Database)?
+                    {
+                        return Err(ProtectionError::Unconfirmed(remaining));
+                    }
+                    return Err(ProtectionError::Queued(remaining));
+                }
+            }
+
+            let authorization = Authorization::Token(raw_token);
+            Ok((addr, auth_wrapper_raw, auth_wrapper, authorization))
+        }
+        // Tokens can't be paid for under the HMAC scheme, so no payment request is offered
+        None if SETTINGS.tokens.scheme == TokenSchemeKind::Hmac => {
+            Err(ProtectionError::Unauthorized)
         }
         None => Err(ProtectionError::MissingToken(
             pub_key_hash.as_ref().to_vec(),
             metadata_hash,
+            size,
+            payments::PaymentFormat::from_request_headers(&header_map),
         )),
     }
 }
@@ -0,0 +1,162 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+
+use dashmap::DashMap;
+use thiserror::Error;
+use warp::{http::Response, hyper::Body, reject::Reject, Filter, Rejection};
+
+use crate::{settings::RateLimit, SETTINGS};
+
+pub const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+
+/// Number of clients tracked before full buckets are pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Error)]
+#[error("rate limited, retry after {0} seconds")]
+pub struct RateLimited(u64);
+
+impl Reject for RateLimited {}
+
+impl RateLimited {
+    /// Convert into a `Response`, carrying a `Retry-After` header.
+    pub fn into_response(&self) -> Response<Body> {
+        Response::builder()
+            .status(429)
+            .header(http::header::RETRY_AFTER, self.0)
+            .body(Body::from(self.to_string()))
+            .unwrap()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter, keyed by client IP.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: Option<&'static RateLimit>,
+    buckets: Arc<DashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Construct new [`RateLimiter`], no limit is applied if `None` is given.
+    pub fn new(limit: Option<&'static RateLimit>) -> Self {
+        Self {
+            limit,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Take a token from the client's bucket.
+    pub fn check(&self, client: IpAddr) -> Result<(), RateLimited> {
+        let limit = match self.limit {
+            Some(some) => some,
+            None => return Ok(()),
+        };
+        let burst = limit.burst as f64;
+
+        if self.buckets.len() > PRUNE_THRESHOLD {
+            self.buckets.retain(|_, bucket| {
+                bucket.tokens + bucket.updated.elapsed().as_secs_f64() * limit.per_second < burst
+            });
+        }
+
+        let now = Instant::now();
+        let mut bucket = self.buckets.entry(client).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens < 1. {
+            // Limits which never refill are rejected by the settings, saturate regardless
+            let wait = (1. - bucket.tokens) / limit.per_second;
+            return Err(RateLimited((wait.max(0.) as u64).saturating_add(1)));
+        }
+        bucket.tokens -= 1.;
+        Ok(())
+    }
+}
+
+/// Determine the client IP, trusting `X-Forwarded-For` only when sent by a trusted proxy.
+pub fn client_ip(remote: Option<SocketAddr>, forwarded_for: Option<&str>) -> IpAddr {
+    let trusted_proxies = &SETTINGS.rate_limits.trusted_proxies;
+    let mut client = remote
+        .map(|addr| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+    // Walk the chain from the nearest hop, stopping at the first untrusted address
+    if let Some(forwarded_for) = forwarded_for {
+        for hop in forwarded_for.rsplit(',') {
+            if !trusted_proxies.contains(&client) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(ip) => client = ip,
+                Err(_) => break,
+            }
+        }
+    }
+    client
+}
+
+/// Filter extracting the client IP.
+pub fn client_addr() -> impl Filter<Extract = (IpAddr,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>(X_FORWARDED_FOR))
+        .map(|remote, forwarded_for: Option<String>| client_ip(remote, forwarded_for.as_deref()))
+}
+
+/// Filter rejecting requests from clients exceeding the limit.
+pub fn rate_limit(limiter: RateLimiter) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    client_addr()
+        .and_then(move |client| {
+            let limiter = limiter.clone();
+            async move { limiter.check(client).map_err(warp::reject::custom) }
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static LIMIT: RateLimit = RateLimit {
+        burst: 2,
+        per_second: 0.5,
+    };
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::new(Some(&LIMIT));
+        let client_a = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+        let client_b = IpAddr::V4(Ipv4Addr::new(5, 6, 7, 8));
+
+        assert!(limiter.check(client_a).is_ok());
+        assert!(limiter.check(client_a).is_ok());
+        let retry_after = limiter.check(client_a).unwrap_err().0;
+        assert!((1..=2).contains(&retry_after));
+        assert!(limiter.check(client_b).is_ok());
+
+        static STALLED: RateLimit = RateLimit {
+            burst: 1,
+            per_second: 0.,
+        };
+        let stalled = RateLimiter::new(Some(&STALLED));
+        assert!(stalled.check(client_a).is_ok());
+        assert_eq!(stalled.check(client_a).unwrap_err().0, u64::MAX);
+
+        let unlimited = RateLimiter::new(None);
+        for _ in 0..10 {
+            assert!(unlimited.check(client_a).is_ok());
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use prost::Message as _;
+use tokio::task;
+use warp::{http::Response, hyper::Body};
+
+use super::PeeringUnavailible;
+use crate::{
+    db::Database,
+    peering::{bucket_entries, DigestCache},
+    SETTINGS,
+};
+
+/// Handles GET requests for the digest of each address bucket.
+///
+/// Digests are cached until the next block, so may lag behind recent updates.
+pub async fn get_sync_digests(
+    digest_cache: DigestCache,
+) -> Result<Response<Body>, PeeringUnavailible> {
+    if !SETTINGS.peering.enabled {
+        return Err(PeeringUnavailible);
+    }
+
+    let digests = digest_cache.get_digests().await;
+    let mut raw_digests = Vec::with_capacity(digests.encoded_len());
+    digests.encode(&mut raw_digests).unwrap(); // This is safe
+    Ok(Response::builder().body(Body::from(raw_digests)).unwrap())
+}
+
+/// Handles GET requests for the entries of an address bucket.
+pub async fn get_sync_bucket(
+    bucket: u8,
+    database: Database,
+) -> Result<Response<Body>, PeeringUnavailible> {
+    if !SETTINGS.peering.enabled {
+        return Err(PeeringUnavailible);
+    }
+
+    let bucket = task::spawn_blocking(move || bucket_entries(&database, bucket))
+        .await
+        .unwrap();
+    let mut raw_bucket = Vec::with_capacity(bucket.encoded_len());
+    bucket.encode(&mut raw_bucket).unwrap(); // This is safe
+    Ok(Response::builder().body(Body::from(raw_bucket)).unwrap())
+}
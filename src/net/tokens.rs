@@ -0,0 +1,144 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use prost::Message as _;
+use ring::digest::{digest, SHA256};
+use rocksdb::Error as RocksError;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use warp::{
+    http::{header::CONTENT_TYPE, Response},
+    hyper::Body,
+    reject::Reject,
+};
+
+use super::{address_decode, AddressDecode, IntoResponse};
+use crate::{db::Database, models::wrapper::AuthWrapper, token::TokenScheme};
+
+/// Maximum size of a token introspection request.
+pub const VALIDATE_TOKEN_LIMIT: u64 = 1_000;
+
+/// Token introspection request.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidateTokenRequest {
+    pub address: String,
+    /// Hex encoded public key, defaults to the public key of the metadata stored under the address.
+    pub public_key: Option<String>,
+    /// Hex encoded SHA256 digest of the metadata payload.
+    pub metadata_digest: String,
+    pub token: String,
+    /// Size, in bytes, of the wrapper the token must have paid for, defaults to the size of the
+    /// stored wrapper.
+    pub size: Option<usize>,
+}
+
+/// Outpoint decoded from a token, the transaction ID is hex encoded.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonOutpoint {
+    pub tx_id: String,
+    pub vout: u32,
+}
+
+/// Token introspection response.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidateTokenResponse {
+    pub valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outpoint: Option<JsonOutpoint>,
+    pub transaction_found: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confirmations: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commitment_matches: Option<bool>,
+}
+
+#[derive(Debug, Error)]
+pub enum ValidateTokenError {
+    #[error("failed to decode request: {0}")]
+    JsonDecode(serde_json::Error),
+    #[error("failed to decode address: {0}")]
+    Address(AddressDecode),
+    #[error("failed to decode hex: {0}")]
+    HexDecode(hex::FromHexError),
+    #[error("no public key given or stored for the address")]
+    MissingPublicKey,
+    #[error("no wrapper size given or stored for the address")]
+    MissingSize,
+    #[error("failed to read from database: {0}")]
+    Database(RocksError),
+}
+
+impl Reject for ValidateTokenError {}
+
+impl IntoResponse for ValidateTokenError {
+    fn to_status(&self) -> u16 {
+        match self {
+            Self::Database(_) => 500,
+            _ => 400,
+        }
+    }
+}
+
+/// Handles token introspection requests, reporting why a token would be accepted or rejected.
+pub async fn validate_token(
+    body: Bytes,
+    token_scheme: Arc<dyn TokenScheme>,
+    database: Database,
+) -> Result<Response<Body>, ValidateTokenError> {
+    let request: ValidateTokenRequest =
+        serde_json::from_slice(&body).map_err(ValidateTokenError::JsonDecode)?;
+    let addr = address_decode(&request.address).map_err(ValidateTokenError::Address)?;
+    let metadata_digest =
+        hex::decode(&request.metadata_digest).map_err(ValidateTokenError::HexDecode)?;
+
+    // Fallback to the public key and size of the stored metadata
+    let stored_wrapper = if request.public_key.is_none() || request.size.is_none() {
+        database
+            .get_metadata(addr.as_body())
+            .map_err(ValidateTokenError::Database)?
+            .map(|database_wrapper| database_wrapper.serialized_auth_wrapper)
+    } else {
+        None
+    };
+    let public_key = match request.public_key {
+        Some(public_key) => hex::decode(public_key).map_err(ValidateTokenError::HexDecode)?,
+        None => stored_wrapper
+            .as_ref()
+            .and_then(|raw_auth_wrapper| AuthWrapper::decode(&raw_auth_wrapper[..]).ok())
+            .map(|auth_wrapper| auth_wrapper.public_key)
+            .ok_or(ValidateTokenError::MissingPublicKey)?,
+    };
+    let size = request
+        .size
+        .or_else(|| stored_wrapper.as_ref().map(Vec::len))
+        .ok_or(ValidateTokenError::MissingSize)?;
+    let pub_key_hash = digest(&SHA256, &public_key);
+
+    // Tokens may be given with or without the POP prefix
+    let token = request.token.trim_start_matches("POP ");
+
+    let (inspection, result) = token_scheme
+        .inspect_token(pub_key_hash.as_ref(), &metadata_digest, token, size)
+        .await;
+
+    let response = ValidateTokenResponse {
+        valid: result.is_ok(),
+        error: result.err().map(|err| err.to_string()),
+        outpoint: inspection.outpoint.map(|(tx_id, vout)| JsonOutpoint {
+            tx_id: hex::encode(tx_id),
+            vout,
+        }),
+        transaction_found: inspection.transaction_found,
+        confirmations: inspection.confirmations,
+        commitment_matches: inspection.commitment_matches,
+    };
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&response).unwrap())) // This is safe
+        .unwrap())
+}
@@ -0,0 +1,250 @@
+use std::time::Duration;
+
+use cashweb::bitcoin_client::{BitcoinClient, HttpClient, HttpError};
+use futures::{channel::mpsc, future::BoxFuture, prelude::*, stream::BoxStream};
+use json_rpc::prelude::RequestFactory;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use tokio::time;
+use tracing::warn;
+
+use super::{BlockNotifier, MempoolAcceptance, Node, NodeError};
+
+/// Delay before the first reconnection to ZMQ, doubled after each consecutive failure.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum delay between reconnections to ZMQ.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+impl From<HttpError> for NodeError {
+    fn from(err: HttpError) -> Self {
+        match err {
+            HttpError::Rpc(err) => Self::Rpc(err.code as i64, err.message),
+            err => Self::Request(err.to_string()),
+        }
+    }
+}
+
+/// Subset of the verbose `getrawtransaction` response.
+#[derive(Deserialize)]
+struct VerboseTransaction {
+    hex: String,
+    confirmations: Option<u32>,
+}
+
+/// Source of block notifications.
+#[derive(Clone)]
+pub enum BlockSource {
+    /// Subscribe to `hashblock` over ZMQ.
+    Zmq,
+    /// Poll `getbestblockhash` at the given interval.
+    Poll(Duration),
+    /// Relay the notifications received by a [`BlockNotifier`].
+    Notify(BlockNotifier),
+}
+
+/// Bitcoin node reached over JSON-RPC, with transaction notifications over ZMQ alongside blocks.
+#[derive(Clone)]
+pub struct BitcoindNode {
+    client: BitcoinClient<HttpClient>,
+    zmq_address: String,
+    block_source: BlockSource,
+}
+
+impl BitcoindNode {
+    /// Construct new [`BitcoindNode`], with block notifications over ZMQ.
+    pub fn new(client: BitcoinClient<HttpClient>, zmq_address: String) -> Self {
+        Self {
+            client,
+            zmq_address,
+            block_source: BlockSource::Zmq,
+        }
+    }
+
+    /// Set the source of block notifications.
+    pub fn with_block_source(mut self, block_source: BlockSource) -> Self {
+        self.block_source = block_source;
+        self
+    }
+
+    /// Subscribe to a ZMQ topic, yielding the message bodies.
+    ///
+    /// The subscription is reestablished, with backoff, whenever it fails or ends.
+    fn subscribe(&self, topic: &'static str) -> BoxStream<'static, Vec<u8>> {
+        let zmq_address = self.zmq_address.clone();
+        let (sender, receiver) = mpsc::unbounded();
+        tokio::spawn(async move {
+            let mut delay = MIN_RECONNECT_DELAY;
+            loop {
+                match connect_zmq(&zmq_address, topic) {
+                    Ok(mut bodies) => {
+                        delay = MIN_RECONNECT_DELAY;
+                        while let Some(body) = bodies.next().await {
+                            if sender.unbounded_send(body).is_err() {
+                                return;
+                            }
+                        }
+                        warn!(message = "zmq subscription ended", topic);
+                    }
+                    Err(err) => {
+                        warn!(message = "failed to subscribe to zmq", topic, error = %err)
+                    }
+                }
+                time::delay_for(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        });
+        receiver.boxed()
+    }
+
+    /// Poll the best block hash, yielding it whenever it changes.
+    fn poll_blocks(&self, interval: Duration) -> BoxStream<'static, Vec<u8>> {
+        let node = self.clone();
+        let (sender, receiver) = mpsc::unbounded();
+        tokio::spawn(async move {
+            let mut interval = time::interval(interval);
+            let mut best_block_hash = None;
+            loop {
+                interval.tick().await;
+                let block_hash = match node.get_best_block_hash().await {
+                    Ok(some) => some,
+                    Err(err) => {
+                        warn!(message = "failed to poll best block", error = %err);
+                        continue;
+                    }
+                };
+                if best_block_hash.as_ref() == Some(&block_hash) {
+                    continue;
+                }
+
+                // The first poll only establishes the tip
+                if best_block_hash.replace(block_hash.clone()).is_some()
+                    && sender.unbounded_send(block_hash).is_err()
+                {
+                    return;
+                }
+            }
+        });
+        receiver.boxed()
+    }
+
+    /// Call a JSON-RPC method, deserializing its result.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: Vec<Value>,
+    ) -> Result<T, NodeError> {
+        let request = self
+            .client
+            .build_request()
+            .method(method)
+            .params(params)
+            .finish()
+            .map_err(|_| NodeError::Request("incomplete request".to_string()))?;
+        let response = self.client.send(request).await.map_err(HttpError::Http)?;
+        if response.is_error() {
+            let err = response.error().unwrap(); // This is safe
+            return Err(HttpError::Rpc(err).into());
+        }
+        let result = response
+            .into_result()
+            .ok_or(HttpError::EmptyResponse)?
+            .map_err(HttpError::Json)?;
+        Ok(result)
+    }
+
+    /// Get the hash of the best block.
+    async fn get_best_block_hash(&self) -> Result<Vec<u8>, NodeError> {
+        let block_hash: String = self.call("getbestblockhash", vec![]).await?;
+        hex::decode(block_hash).map_err(|err| NodeError::Request(err.to_string()))
+    }
+}
+
+/// Connect to a ZMQ topic, yielding the message bodies.
+fn connect_zmq(zmq_address: &str, topic: &str) -> Result<BoxStream<'static, Vec<u8>>, NodeError> {
+    let subscriber = async_zmq::subscribe(zmq_address)
+        .map_err(|err| NodeError::Request(err.to_string()))?
+        .connect()
+        .map_err(|err| NodeError::Request(err.to_string()))?;
+    subscriber
+        .set_subscribe(topic)
+        .map_err(|err| NodeError::Request(err.to_string()))?;
+
+    let bodies = subscriber.filter_map(|multipart| async move {
+        multipart.ok()?.get(1).map(|body| body.as_ref().to_vec())
+    });
+    Ok(bodies.boxed())
+}
+
+impl Node for BitcoindNode {
+    fn send_tx<'a>(&'a self, raw_tx: &'a [u8]) -> BoxFuture<'a, Result<String, NodeError>> {
+        async move { Ok(self.client.send_tx(raw_tx).await?) }.boxed()
+    }
+
+    fn get_raw_transaction<'a>(
+        &'a self,
+        tx_id: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>, NodeError>> {
+        async move { Ok(self.client.get_raw_transaction(tx_id).await?) }.boxed()
+    }
+
+    fn get_transaction<'a>(
+        &'a self,
+        tx_id: &'a [u8],
+    ) -> BoxFuture<'a, Result<(Vec<u8>, u32), NodeError>> {
+        async move {
+            let params = vec![Value::String(hex::encode(tx_id)), Value::Bool(true)];
+            let transaction: VerboseTransaction = self.call("getrawtransaction", params).await?;
+            let raw_tx =
+                hex::decode(&transaction.hex).map_err(|err| NodeError::Request(err.to_string()))?;
+            Ok((raw_tx, transaction.confirmations.unwrap_or_default()))
+        }
+        .boxed()
+    }
+
+    fn get_confirmations<'a>(&'a self, tx_id: &'a [u8]) -> BoxFuture<'a, Result<u32, NodeError>> {
+        async move {
+            let params = vec![Value::String(hex::encode(tx_id)), Value::Bool(true)];
+            let transaction: VerboseTransaction = self.call("getrawtransaction", params).await?;
+            Ok(transaction.confirmations.unwrap_or_default())
+        }
+        .boxed()
+    }
+
+    fn get_block_count(&self) -> BoxFuture<'_, Result<u32, NodeError>> {
+        self.call("getblockcount", vec![]).boxed()
+    }
+
+    fn test_mempool_accept<'a>(
+        &'a self,
+        raw_tx: &'a [u8],
+    ) -> BoxFuture<'a, Result<MempoolAcceptance, NodeError>> {
+        async move {
+            let params = vec![Value::Array(vec![Value::String(hex::encode(raw_tx))])];
+            let mut acceptances: Vec<MempoolAcceptance> =
+                self.call("testmempoolaccept", params).await?;
+            acceptances
+                .pop()
+                .ok_or_else(|| NodeError::Request("empty response".to_string()))
+        }
+        .boxed()
+    }
+
+    fn subscribe_blocks(&self) -> Result<BoxStream<'static, Vec<u8>>, NodeError> {
+        let blocks = match &self.block_source {
+            BlockSource::Zmq => self.subscribe("hashblock"),
+            BlockSource::Poll(interval) => self.poll_blocks(*interval),
+            BlockSource::Notify(notifier) => notifier.subscribe(),
+        };
+        Ok(blocks)
+    }
+
+    fn subscribe_transactions(&self) -> Result<BoxStream<'static, Vec<u8>>, NodeError> {
+        // ZMQ is only expected to be available if blocks are received over it
+        let transactions = match &self.block_source {
+            BlockSource::Zmq => self.subscribe("rawtx"),
+            BlockSource::Poll(_) | BlockSource::Notify(_) => stream::empty().boxed(),
+        };
+        Ok(transactions)
+    }
+}
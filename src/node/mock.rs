@@ -0,0 +1,207 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    channel::mpsc::{self, UnboundedSender},
+    future::{self, BoxFuture},
+    prelude::*,
+    stream::BoxStream,
+};
+use ring::digest::{digest, SHA256};
+
+use super::{tx_id, MempoolAcceptance, Node, NodeError, RPC_INVALID_ADDRESS_OR_KEY};
+
+/// Error code returned by bitcoind for transactions rejected by the mempool.
+const RPC_VERIFY_REJECTED: i64 = -26;
+
+#[derive(Default)]
+struct MockState {
+    transactions: HashMap<Vec<u8>, Vec<u8>>,
+    block_heights: HashMap<Vec<u8>, u32>,
+    broadcasts: Vec<Vec<u8>>,
+    rejections: HashMap<Vec<u8>, String>,
+    block_subscribers: Vec<UnboundedSender<Vec<u8>>>,
+    transaction_subscribers: Vec<UnboundedSender<Vec<u8>>>,
+    height: u32,
+}
+
+/// In-process node, accepting any transaction which hasn't been explicitly rejected.
+///
+/// Intended for tests, allowing the server to run without a bitcoind.
+#[derive(Clone, Default)]
+pub struct MockNode {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockNode {
+    /// Construct new [`MockNode`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a transaction to the mempool without broadcasting it.
+    pub fn insert_transaction(&self, raw_tx: Vec<u8>) -> Vec<u8> {
+        let tx_id = tx_id(&raw_tx);
+        let mut state = self.state.lock().unwrap();
+        state.transactions.insert(tx_id.clone(), raw_tx);
+        tx_id
+    }
+
+    /// Reject a transaction from the mempool with the given reason.
+    pub fn reject(&self, tx_id: Vec<u8>, reason: String) {
+        let mut state = self.state.lock().unwrap();
+        state.rejections.insert(tx_id, reason);
+    }
+
+    /// Get the transactions broadcast to the node, in order.
+    pub fn broadcasts(&self) -> Vec<Vec<u8>> {
+        self.state.lock().unwrap().broadcasts.clone()
+    }
+
+    /// Mine a block containing the mempool, notifying subscribers and returning its hash.
+    pub fn mine_block(&self) -> Vec<u8> {
+        let mut state = self.state.lock().unwrap();
+        state.height += 1;
+        let MockState {
+            transactions,
+            block_heights,
+            height,
+            ..
+        } = &mut *state;
+        for tx_id in transactions.keys() {
+            block_heights.entry(tx_id.clone()).or_insert(*height);
+        }
+        let block_hash = digest(&SHA256, &state.height.to_le_bytes())
+            .as_ref()
+            .to_vec();
+        state
+            .block_subscribers
+            .retain(|subscriber| subscriber.unbounded_send(block_hash.clone()).is_ok());
+        block_hash
+    }
+
+    /// Remove a transaction, as if it were reorged out and double-spent.
+    ///
+    /// The conflicting transaction, if given, is announced to subscribers.
+    pub fn remove_transaction(&self, tx_id: &[u8], conflicting_tx: Option<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        state.transactions.remove(tx_id);
+        state.block_heights.remove(tx_id);
+        if let Some(raw_tx) = conflicting_tx {
+            state
+                .transactions
+                .insert(super::tx_id(&raw_tx), raw_tx.clone());
+            state
+                .transaction_subscribers
+                .retain(|subscriber| subscriber.unbounded_send(raw_tx.clone()).is_ok());
+        }
+    }
+
+    fn rejection(&self, tx_id: &[u8]) -> Option<String> {
+        self.state.lock().unwrap().rejections.get(tx_id).cloned()
+    }
+}
+
+impl Node for MockNode {
+    fn send_tx<'a>(&'a self, raw_tx: &'a [u8]) -> BoxFuture<'a, Result<String, NodeError>> {
+        let tx_id = tx_id(raw_tx);
+        let result = match self.rejection(&tx_id) {
+            Some(reason) => Err(NodeError::Rpc(RPC_VERIFY_REJECTED, reason)),
+            None => {
+                let mut state = self.state.lock().unwrap();
+                state.transactions.insert(tx_id.clone(), raw_tx.to_vec());
+                state.broadcasts.push(raw_tx.to_vec());
+                state
+                    .transaction_subscribers
+                    .retain(|subscriber| subscriber.unbounded_send(raw_tx.to_vec()).is_ok());
+                Ok(hex::encode(tx_id))
+            }
+        };
+        future::ready(result).boxed()
+    }
+
+    fn get_raw_transaction<'a>(
+        &'a self,
+        tx_id: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>, NodeError>> {
+        let result = self
+            .state
+            .lock()
+            .unwrap()
+            .transactions
+            .get(tx_id)
+            .cloned()
+            .ok_or_else(|| {
+                NodeError::Rpc(
+                    RPC_INVALID_ADDRESS_OR_KEY,
+                    "No such mempool or blockchain transaction".to_string(),
+                )
+            });
+        future::ready(result).boxed()
+    }
+
+    fn get_transaction<'a>(
+        &'a self,
+        tx_id: &'a [u8],
+    ) -> BoxFuture<'a, Result<(Vec<u8>, u32), NodeError>> {
+        let state = self.state.lock().unwrap();
+        let result = match state.transactions.get(tx_id) {
+            Some(raw_tx) => {
+                let confirmations = state
+                    .block_heights
+                    .get(tx_id)
+                    .map(|block_height| state.height - block_height + 1)
+                    .unwrap_or_default();
+                Ok((raw_tx.clone(), confirmations))
+            }
+            None => Err(NodeError::Rpc(
+                RPC_INVALID_ADDRESS_OR_KEY,
+                "No such mempool or blockchain transaction".to_string(),
+            )),
+        };
+        future::ready(result).boxed()
+    }
+
+    fn get_confirmations<'a>(&'a self, tx_id: &'a [u8]) -> BoxFuture<'a, Result<u32, NodeError>> {
+        self.get_transaction(tx_id)
+            .map_ok(|(_, confirmations)| confirmations)
+            .boxed()
+    }
+
+    fn get_block_count(&self) -> BoxFuture<'_, Result<u32, NodeError>> {
+        let height = self.state.lock().unwrap().height;
+        future::ready(Ok(height)).boxed()
+    }
+
+    fn test_mempool_accept<'a>(
+        &'a self,
+        raw_tx: &'a [u8],
+    ) -> BoxFuture<'a, Result<MempoolAcceptance, NodeError>> {
+        let tx_id = tx_id(raw_tx);
+        let reject_reason = self.rejection(&tx_id);
+        let acceptance = MempoolAcceptance {
+            txid: hex::encode(tx_id),
+            allowed: reject_reason.is_none(),
+            reject_reason,
+        };
+        future::ready(Ok(acceptance)).boxed()
+    }
+
+    fn subscribe_blocks(&self) -> Result<BoxStream<'static, Vec<u8>>, NodeError> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.state.lock().unwrap().block_subscribers.push(sender);
+        Ok(receiver.boxed())
+    }
+
+    fn subscribe_transactions(&self) -> Result<BoxStream<'static, Vec<u8>>, NodeError> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.state
+            .lock()
+            .unwrap()
+            .transaction_subscribers
+            .push(sender);
+        Ok(receiver.boxed())
+    }
+}
@@ -0,0 +1,106 @@
+pub mod bitcoind;
+pub mod mock;
+pub mod notifier;
+
+pub use bitcoind::*;
+pub use mock::*;
+pub use notifier::*;
+
+use futures::{future::BoxFuture, stream::BoxStream};
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Result of a `testmempoolaccept` call for a single transaction.
+#[derive(Debug, Deserialize)]
+pub struct MempoolAcceptance {
+    pub txid: String,
+    pub allowed: bool,
+    #[serde(rename = "reject-reason")]
+    pub reject_reason: Option<String>,
+}
+
+/// Error code returned by bitcoind for unknown transactions.
+pub const RPC_INVALID_ADDRESS_OR_KEY: i64 = -5;
+
+#[derive(Debug, Error)]
+pub enum NodeError {
+    /// The node processed the request and returned an error.
+    #[error("node returned error {0}: {1}")]
+    Rpc(i64, String),
+    /// The request failed to reach the node or the response was malformed.
+    #[error("node request failed: {0}")]
+    Request(String),
+}
+
+impl NodeError {
+    /// Whether the node reported the requested transaction doesn't exist.
+    ///
+    /// Other errors, such as those returned while the node is warming up, are transient.
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Self::Rpc(RPC_INVALID_ADDRESS_OR_KEY, _))
+    }
+}
+
+/// Interface to a Bitcoin node.
+pub trait Node: Clone + Send + Sync + 'static {
+    /// Broadcast a transaction, returning its ID.
+    fn send_tx<'a>(&'a self, raw_tx: &'a [u8]) -> BoxFuture<'a, Result<String, NodeError>>;
+
+    /// Get a transaction by its ID.
+    fn get_raw_transaction<'a>(
+        &'a self,
+        tx_id: &'a [u8],
+    ) -> BoxFuture<'a, Result<Vec<u8>, NodeError>>;
+
+    /// Get a transaction by its ID, along with its number of confirmations.
+    fn get_transaction<'a>(
+        &'a self,
+        tx_id: &'a [u8],
+    ) -> BoxFuture<'a, Result<(Vec<u8>, u32), NodeError>>;
+
+    /// Get the number of confirmations of a transaction, zero if it is in the mempool.
+    fn get_confirmations<'a>(&'a self, tx_id: &'a [u8]) -> BoxFuture<'a, Result<u32, NodeError>>;
+
+    /// Get the height of the most-work chain.
+    fn get_block_count(&self) -> BoxFuture<'_, Result<u32, NodeError>>;
+
+    /// Check whether a transaction would be accepted to the mempool.
+    fn test_mempool_accept<'a>(
+        &'a self,
+        raw_tx: &'a [u8],
+    ) -> BoxFuture<'a, Result<MempoolAcceptance, NodeError>>;
+
+    /// Subscribe to new blocks, yielding their hashes.
+    fn subscribe_blocks(&self) -> Result<BoxStream<'static, Vec<u8>>, NodeError>;
+
+    /// Subscribe to transactions entering the mempool or a block, yielding the raw transactions.
+    ///
+    /// The stream is empty if the node doesn't notify transactions.
+    fn subscribe_transactions(&self) -> Result<BoxStream<'static, Vec<u8>>, NodeError>;
+}
+
+/// Calculate the ID of a raw transaction.
+pub fn tx_id(raw_tx: &[u8]) -> Vec<u8> {
+    let mut tx_id = digest(&SHA256, digest(&SHA256, raw_tx).as_ref())
+        .as_ref()
+        .to_vec();
+    tx_id.reverse();
+    tx_id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn not_found() {
+        let err = NodeError::Rpc(
+            RPC_INVALID_ADDRESS_OR_KEY,
+            "No such mempool or blockchain transaction".to_string(),
+        );
+        assert!(err.is_not_found());
+        assert!(!NodeError::Rpc(-28, "Loading block index...".to_string()).is_not_found());
+        assert!(!NodeError::Request("connection refused".to_string()).is_not_found());
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::{Arc, Mutex};
+
+use futures::{
+    channel::mpsc::{self, UnboundedSender},
+    prelude::*,
+    stream::BoxStream,
+};
+
+/// Relays block notifications, such as those posted by bitcoind's `-blocknotify`, to subscribers.
+#[derive(Clone, Default)]
+pub struct BlockNotifier {
+    subscribers: Arc<Mutex<Vec<UnboundedSender<Vec<u8>>>>>,
+}
+
+impl BlockNotifier {
+    /// Subscribe to notifications, yielding block hashes.
+    pub fn subscribe(&self) -> BoxStream<'static, Vec<u8>> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver.boxed()
+    }
+
+    /// Notify subscribers of a block.
+    pub fn notify(&self, block_hash: Vec<u8>) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.unbounded_send(block_hash.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn relays_notifications() {
+        let notifier = BlockNotifier::default();
+        let mut blocks_a = notifier.subscribe();
+        let blocks_b = notifier.subscribe();
+        drop(blocks_b);
+
+        notifier.notify(vec![1; 32]);
+        notifier.notify(vec![2; 32]);
+        assert_eq!(blocks_a.next().await, Some(vec![1; 32]));
+        assert_eq!(blocks_a.next().await, Some(vec![2; 32]));
+        assert_eq!(notifier.subscribers.lock().unwrap().len(), 1);
+    }
+}
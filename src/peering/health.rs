@@ -0,0 +1,240 @@
+use std::{
+    fmt,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use cashweb::keyserver_client::services::GetMetadataError;
+use dashmap::DashMap;
+use futures::{future::BoxFuture, prelude::*};
+use hyper::{Body, Request, Response, Uri};
+use tower_service::Service;
+use tracing::warn;
+
+/// Score change on a successful request.
+const SUCCESS_REWARD: i32 = 1;
+/// Score change on a failed request.
+const FAILURE_PENALTY: i32 = -10;
+/// Score change on serving invalid data.
+const INVALID_PENALTY: i32 = -50;
+/// Maximum score a peer can accumulate.
+const MAX_SCORE: i32 = 100;
+/// Score at which a peer is banned.
+const BAN_SCORE: i32 = -100;
+/// Duration of the first ban, doubling with each subsequent ban.
+const BASE_BAN_DURATION: Duration = Duration::from_secs(60);
+/// Maximum ban duration.
+const MAX_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+/// Weight of the latest observation in the average latency.
+const LATENCY_WEIGHT: f64 = 0.2;
+
+/// Statistics about a peer's behaviour.
+#[derive(Clone, Debug, Default)]
+pub struct PeerStats {
+    pub successes: u64,
+    pub failures: u64,
+    /// Number of times the peer served invalid data.
+    pub invalid: u64,
+    /// Moving average of the request latency.
+    pub latency: Option<Duration>,
+    pub score: i32,
+    /// Number of times the peer has been banned.
+    pub bans: u32,
+    pub banned_until: Option<Instant>,
+}
+
+impl PeerStats {
+    /// Whether the peer is currently banned.
+    pub fn is_banned(&self) -> bool {
+        self.banned_until
+            .map(|banned_until| banned_until > Instant::now())
+            .unwrap_or_default()
+    }
+
+    /// Adjust the score, banning the peer if it drops too low.
+    ///
+    /// Returns the duration of the ban, if one was imposed.
+    fn adjust_score(&mut self, delta: i32) -> Option<Duration> {
+        self.score = (self.score + delta).min(MAX_SCORE);
+        if self.score > BAN_SCORE {
+            return None;
+        }
+        let duration = BASE_BAN_DURATION
+            .checked_mul(2u32.saturating_pow(self.bans))
+            .unwrap_or(MAX_BAN_DURATION)
+            .min(MAX_BAN_DURATION);
+        self.banned_until = Some(Instant::now() + duration);
+        self.bans += 1;
+        self.score = 0;
+        Some(duration)
+    }
+}
+
+/// Key identifying the peer a URI belongs to.
+pub fn peer_key(uri: &Uri) -> String {
+    uri.authority()
+        .map(|authority| authority.to_string())
+        .unwrap_or_else(|| uri.to_string())
+}
+
+/// Health statistics of peers, keyed by their authority.
+#[derive(Clone, Debug, Default)]
+pub struct PeerHealth {
+    stats: Arc<DashMap<String, PeerStats>>,
+}
+
+impl PeerHealth {
+    fn adjust(&self, uri: &Uri, update: impl FnOnce(&mut PeerStats) -> i32) {
+        let key = peer_key(uri);
+        let mut stats = self.stats.entry(key.clone()).or_default();
+        let delta = update(&mut stats);
+        if let Some(duration) = stats.adjust_score(delta) {
+            warn!(message = "banned peer", peer = %key, duration = ?duration);
+        }
+    }
+
+    /// Record a successful request to a peer.
+    pub fn record_success(&self, uri: &Uri, latency: Duration) {
+        self.adjust(uri, |stats| {
+            stats.successes += 1;
+            stats.latency = Some(match stats.latency {
+                Some(average) => {
+                    average.mul_f64(1. - LATENCY_WEIGHT) + latency.mul_f64(LATENCY_WEIGHT)
+                }
+                None => latency,
+            });
+            SUCCESS_REWARD
+        })
+    }
+
+    /// Record a failed request to a peer.
+    pub fn record_failure(&self, uri: &Uri) {
+        self.adjust(uri, |stats| {
+            stats.failures += 1;
+            FAILURE_PENALTY
+        })
+    }
+
+    /// Record a peer serving invalid data.
+    pub fn record_invalid(&self, uri: &Uri) {
+        self.adjust(uri, |stats| {
+            stats.invalid += 1;
+            INVALID_PENALTY
+        })
+    }
+
+    /// Ban a peer for the given duration, regardless of its score.
+    pub fn ban(&self, uri: &Uri, duration: Duration) {
+        let key = peer_key(uri);
+        let mut stats = self.stats.entry(key.clone()).or_default();
+        stats.banned_until = Some(Instant::now() + duration);
+        stats.bans += 1;
+        stats.score = 0;
+        warn!(message = "banned peer", peer = %key, duration = ?duration);
+    }
+
+    /// Whether a peer is currently banned.
+    pub fn is_banned(&self, uri: &Uri) -> bool {
+        self.stats
+            .get(&peer_key(uri))
+            .map(|stats| stats.is_banned())
+            .unwrap_or_default()
+    }
+
+    /// Get the statistics of a peer.
+    pub fn get_stats(&self, uri: &Uri) -> Option<PeerStats> {
+        self.stats.get(&peer_key(uri)).map(|stats| stats.clone())
+    }
+}
+
+/// Whether a metadata error was caused by the peer serving invalid data.
+///
+/// Metadata published by a peer's operator is served without a token, it is skipped but not
+/// penalized.
+pub fn is_invalid_metadata<E: fmt::Debug + fmt::Display>(err: &GetMetadataError<E>) -> bool {
+    matches!(
+        err,
+        GetMetadataError::MetadataDecode(_)
+            | GetMetadataError::AuthWrapperDecode(_)
+            | GetMetadataError::AuthWrapperParse(_)
+            | GetMetadataError::AuthWrapperVerify(_)
+    )
+}
+
+/// Client recording the latency and failures of requests in the health of peers.
+#[derive(Clone, Debug)]
+pub struct MeteredClient<S> {
+    inner: S,
+    health: PeerHealth,
+}
+
+impl<S> MeteredClient<S> {
+    /// Construct new [`MeteredClient`].
+    pub fn new(inner: S, health: PeerHealth) -> Self {
+        Self { inner, health }
+    }
+}
+
+impl<S> Service<Request<Body>> for MeteredClient<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response<Body>, S::Error>>;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(context)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let uri = request.uri().clone();
+        let health = self.health.clone();
+        let start = Instant::now();
+        let response_fut = self.inner.call(request);
+        async move {
+            let result = response_fut.await;
+            match &result {
+                Ok(response) if !response.status().is_server_error() => {
+                    health.record_success(&uri, start.elapsed())
+                }
+                _ => health.record_failure(&uri),
+            }
+            result
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escalating_bans() {
+        let health = PeerHealth::default();
+        let uri: Uri = "http://peer:8080/keys/abc".parse().unwrap();
+        let other_uri: Uri = "http://peer:8080/peers".parse().unwrap();
+
+        health.record_success(&uri, Duration::from_millis(100));
+        assert!(!health.is_banned(&uri));
+
+        // Invalid data bans faster than failures
+        for _ in 0..3 {
+            health.record_invalid(&uri);
+        }
+        assert!(health.is_banned(&other_uri));
+        let first_ban = health.get_stats(&uri).unwrap().banned_until.unwrap();
+
+        for _ in 0..10 {
+            health.record_failure(&uri);
+        }
+        let stats = health.get_stats(&uri).unwrap();
+        assert_eq!(stats.bans, 2);
+        assert_eq!(stats.failures, 10);
+        assert_eq!(stats.invalid, 3);
+        assert!(stats.banned_until.unwrap() - first_ban > BASE_BAN_DURATION / 2);
+    }
+}
@@ -1,29 +1,64 @@
+mod health;
+mod proxy;
+mod sampling;
+mod sync;
+mod tls;
 mod token_cache;
 
+pub use health::*;
+pub use proxy::*;
+pub use sampling::*;
+pub use sync::*;
+pub use tls::*;
 pub use token_cache::*;
 
-use std::{fmt, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use bytes::{Bytes, BytesMut};
 use cashweb::keyserver_client::{
-    services::{GetPeersError, SampleError},
-    KeyserverManager,
+    select_auth_wrapper,
+    services::{GetMetadata, GetMetadataError, SampleError, SampleRequest},
+    KeyserverClient, KeyserverManager, MetadataPackage, SampleResponse,
 };
+use dashmap::DashMap;
+use futures::{future, stream, StreamExt};
 use hyper::{
-    client::{Client as HttpClient, HttpConnector},
-    Body, Request, Response, Uri,
+    client::Client as HttpClient, header::AUTHORIZATION, http::uri::InvalidUri, Body, Method,
+    Request, Response, Uri,
 };
-use hyper_tls::HttpsConnector;
 use prost::Message as _;
 use rocksdb::Error as RocksError;
-use tokio::sync::RwLock;
+use thiserror::Error;
+use tokio::{sync::RwLock, time};
 use tower_service::Service;
-use tracing::warn;
+use tracing::{debug, info, warn};
 
 use crate::{
     db::Database,
-    models::keyserver::{Peer, Peers},
+    models::{
+        database::ExclusionRecord,
+        keyserver::{Peer, Peers},
+    },
+    net::{self, KeyserverInfo},
+    settings::SamplingStrategyKind,
+    SETTINGS,
 };
 
+/// Maximum number of concurrent requests made while crawling peers.
+const CRAWL_CONCURRENCY: usize = 16;
+
+/// Maximum size of the peer list served by a peer.
+const PEERS_LIMIT: u64 = 1_000_000;
+
+/// Maximum size of the info served by a peer.
+const INFO_LIMIT: u64 = 10_000;
+
 pub fn parse_uri_warn(uri_str: &str) -> Option<Uri> {
     let uri = uri_str.parse();
     match uri {
@@ -35,10 +70,19 @@ pub fn parse_uri_warn(uri_str: &str) -> Option<Uri> {
     }
 }
 
+/// Client used to communicate with peers.
+pub type PeerClient = MeteredClient<HttpClient<PeerTlsConnector>>;
+
 #[derive(Clone)]
 pub struct PeerHandler<S> {
+    client: S,
     keyserver_manager: KeyserverManager<S>,
     peers_cache: Arc<RwLock<Vec<u8>>>,
+    known_uris: Arc<RwLock<Vec<Uri>>>,
+    health: PeerHealth,
+    sampling: Arc<dyn SamplingStrategy>,
+    /// Peers excluded by an operator, along with the end of their ban, if they weren't removed.
+    exclusions: Arc<DashMap<Uri, Option<SystemTime>>>,
 }
 
 fn uris_to_peers(uris: &[Uri]) -> Peers {
@@ -57,16 +101,33 @@ fn uris_to_raw_peers(uris: &[Uri]) -> Vec<u8> {
     buffer
 }
 
-impl PeerHandler<HttpClient<HttpsConnector<HttpConnector>>> {
+impl PeerHandler<PeerClient> {
     /// Construct new [`PeerHandler`].
     pub fn new(uris: Vec<Uri>) -> Self {
-        let https = HttpsConnector::new();
-        let http_client = HttpClient::builder().build(https);
+        let proxy = SETTINGS.peering.proxy.map(|address| SocksProxy {
+            address,
+            onion_only: SETTINGS.peering.proxy_onion_only,
+        });
+        let connector = PeerTlsConnector::new(PeerConnector::new(proxy), &SETTINGS.peering.tls)
+            .expect("invalid peering.tls settings");
+        let health = PeerHealth::default();
+        let http_client =
+            MeteredClient::new(HttpClient::builder().build(connector), health.clone());
         let peers_cache = Arc::new(RwLock::new(uris_to_raw_peers(&uris)));
-        let keyserver_manager = KeyserverManager::from_service(http_client, uris);
+        let known_uris = Arc::new(RwLock::new(uris.clone()));
+        let keyserver_manager = KeyserverManager::from_service(http_client.clone(), uris);
+        let sampling: Arc<dyn SamplingStrategy> = match SETTINGS.peering.sampling {
+            SamplingStrategyKind::Uniform => Arc::new(UniformSampling),
+            SamplingStrategyKind::Weighted => Arc::new(WeightedSampling::new(health.clone())),
+        };
         Self {
+            client: http_client,
             keyserver_manager,
             peers_cache,
+            known_uris,
+            health,
+            sampling,
+            exclusions: Default::default(),
         }
     }
 }
@@ -83,7 +144,158 @@ where
         self.keyserver_manager.get_uris().read().await.clone()
     }
 
+    pub fn get_health(&self) -> &PeerHealth {
+        &self.health
+    }
+
+    /// Choose up to `size` of the given peers using the configured sampling strategy.
+    pub fn sample_peers(&self, uris: &[Uri], size: usize) -> Vec<Uri> {
+        self.sampling.sample(uris, size)
+    }
+
+    /// Add a peer to the known peers, returning whether it was previously unknown.
+    ///
+    /// Peers removed by an operator aren't added.
+    pub async fn add_peer(&self, uri: Uri) -> bool {
+        if self.is_removed(&uri) {
+            return false;
+        }
+        {
+            let mut known_uris = self.known_uris.write().await;
+            if known_uris.contains(&uri) {
+                return false;
+            }
+            known_uris.push(uri);
+        }
+        self.refresh_peers().await;
+        true
+    }
+
+    /// Remove a peer from the known peers, returning whether it was known.
+    pub async fn remove_peer(&self, uri: &Uri) -> bool {
+        {
+            let mut known_uris = self.known_uris.write().await;
+            let len = known_uris.len();
+            known_uris.retain(|known_uri| known_uri != uri);
+            if known_uris.len() == len {
+                return false;
+            }
+        }
+        self.refresh_peers().await;
+        true
+    }
+
+    /// Get the known peers, including those which are banned.
+    pub async fn get_known_urls(&self) -> Vec<Uri> {
+        self.known_uris.read().await.clone()
+    }
+
+    /// Set the known peers, sampling only those which aren't banned.
     pub async fn set_peers(&self, uris: Vec<Uri>) {
+        *self.known_uris.write().await = uris;
+        self.refresh_peers().await;
+    }
+
+    /// Whether the peer was removed by an operator.
+    pub fn is_removed(&self, uri: &Uri) -> bool {
+        self.exclusions
+            .get(uri)
+            .map(|banned_until| banned_until.is_none())
+            .unwrap_or_default()
+    }
+
+    /// Whether the peer is banned, either for its behaviour or by an operator.
+    pub fn is_banned(&self, uri: &Uri) -> bool {
+        let operator_banned = self
+            .exclusions
+            .get(uri)
+            .and_then(|banned_until| *banned_until)
+            .map(|banned_until| banned_until > SystemTime::now())
+            .unwrap_or_default();
+        operator_banned || self.health.is_banned(uri)
+    }
+
+    /// Load the peers excluded by an operator from the database, dropping expired bans.
+    pub async fn load_exclusions(&self, database: &Database) -> Result<(), RocksError> {
+        let now = SystemTime::now();
+        for (url, exclusion_record) in database.get_exclusions() {
+            let banned_until = match exclusion_record.banned_until {
+                0 => None,
+                secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+            };
+            match parse_uri_warn(&url) {
+                Some(uri) if banned_until.map(|until| until > now).unwrap_or(true) => {
+                    self.exclusions.insert(uri, banned_until);
+                }
+                _ => database.delete_exclusion(&url)?,
+            }
+        }
+        self.refresh_peers().await;
+        Ok(())
+    }
+
+    /// Exclude a peer, persisting the exclusion. Removed peers are excluded until included again.
+    pub async fn exclude(
+        &self,
+        database: &Database,
+        uri: Uri,
+        banned_until: Option<SystemTime>,
+    ) -> Result<(), RocksError> {
+        let exclusion_record = ExclusionRecord {
+            banned_until: banned_until
+                .map(|until| {
+                    until
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap() // This is safe
+                        .as_secs()
+                })
+                .unwrap_or_default(),
+        };
+        database.put_exclusion(&uri.to_string(), &exclusion_record)?;
+        self.exclusions.insert(uri, banned_until);
+        self.refresh_peers().await;
+        Ok(())
+    }
+
+    /// Lift the exclusion of a peer.
+    pub fn include(&self, database: &Database, uri: &Uri) -> Result<(), RocksError> {
+        database.delete_exclusion(&uri.to_string())?;
+        self.exclusions.remove(uri);
+        Ok(())
+    }
+
+    /// Exclude banned peers from sampling, and restore those whose ban has expired.
+    ///
+    /// Peers removed by an operator are dropped. At most `max_peers` are kept, preferring seeds and
+    /// then the best scored peers.
+    pub async fn refresh_peers(&self) {
+        let mut known_uris = self.known_uris.write().await;
+        known_uris.retain(|uri| !self.is_removed(uri));
+        let seed_uris: Vec<Uri> = SETTINGS
+            .peering
+            .peers
+            .iter()
+            .filter_map(|peer_str| peer_str.parse().ok())
+            .collect();
+        known_uris.sort_by_cached_key(|uri| {
+            let score = self
+                .health
+                .get_stats(uri)
+                .map(|stats| stats.score)
+                .unwrap_or_default();
+            (
+                !seed_uris.contains(uri),
+                self.is_banned(uri),
+                Reverse(score),
+            )
+        });
+        known_uris.truncate(SETTINGS.peering.max_peers as usize);
+
+        let uris: Vec<Uri> = known_uris
+            .iter()
+            .filter(|uri| !self.is_banned(uri))
+            .cloned()
+            .collect();
         let mut peer_cache_write = self.peers_cache.write().await;
         let uris_shared = self.keyserver_manager.get_uris();
         let mut uris_write = uris_shared.write().await;
@@ -108,19 +320,318 @@ where
     S::Future: Send,
     S::Error: fmt::Debug + Send + fmt::Display,
 {
-    pub async fn inflate(&self) -> Result<(), SampleError<GetPeersError<S::Error>>> {
-        // Crawl peers, collecting Peers
-        let aggregate_response = self.get_keyserver_manager().crawl_peers().await?;
-        // TODO: Ban misbehaviour
-
-        // Collect URIs
-        let uris = aggregate_response
-            .response
+    /// Crawl the known peers, merging the peers they list with the seeds.
+    ///
+    /// At most `max_peers` peers are taken from each peer's list, and the known peers are capped
+    /// at `max_peers` plus the seeds before their compatibility is checked.
+    pub async fn inflate(&self) -> Result<(), CrawlError> {
+        let max_peers = SETTINGS.peering.max_peers as usize;
+        let known_uris = self.known_uris.read().await.clone();
+
+        // Fetch the peers of each known peer, failures are recorded by the client
+        let responses: Vec<_> = stream::iter(known_uris.iter().cloned())
+            .map(|uri| async move {
+                let result = self.get_peers(&uri).await;
+                (uri, result)
+            })
+            .buffer_unordered(CRAWL_CONCURRENCY)
+            .collect()
+            .await;
+        if !responses.is_empty() && responses.iter().all(|(_, result)| result.is_err()) {
+            return Err(CrawlError::Unreachable);
+        }
+
+        // Merge known peers and seeds with a bounded number of discovered peers
+        let seed_uris: Vec<Uri> = SETTINGS
+            .peering
             .peers
-            .into_iter()
-            .filter_map(|peer| parse_uri_warn(&peer.url))
+            .iter()
+            .filter_map(|peer_str| parse_uri_warn(peer_str))
             .collect();
+        let capacity = max_peers + seed_uris.len();
+        let mut seen = HashSet::new();
+        let mut uris = Vec::new();
+        for uri in known_uris.into_iter().chain(seed_uris) {
+            if seen.insert(uri.clone()) && !self.is_removed(&uri) {
+                uris.push(uri);
+            }
+        }
+        for (uri, result) in responses {
+            let peers = match result {
+                Ok(some) => some,
+                Err(PeerRequestError::Decode(_)) => {
+                    // Penalize peers serving malformed peer lists
+                    self.health.record_invalid(&uri);
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            let discovered_uris = peers
+                .peers
+                .iter()
+                .take(max_peers)
+                .filter_map(|peer| parse_uri_warn(&peer.url));
+            for uri in discovered_uris {
+                if uris.len() >= capacity {
+                    break;
+                }
+                if seen.insert(uri.clone()) && !self.is_removed(&uri) {
+                    uris.push(uri);
+                }
+            }
+        }
+
+        // Skip peers on another network or with incompatible limits
+        let local_info = &KeyserverInfo::local();
+        let uris = stream::iter(uris)
+            .map(|uri| async move {
+                let compatible = self.is_compatible(&uri, local_info).await;
+                (uri, compatible)
+            })
+            .buffered(CRAWL_CONCURRENCY)
+            .filter_map(|(uri, compatible)| {
+                future::ready(if compatible { Some(uri) } else { None })
+            })
+            .collect()
+            .await;
         self.set_peers(uris).await;
         Ok(())
     }
+
+    /// Sample metadata from peers and select the latest.
+    #[allow(clippy::type_complexity)]
+    pub async fn sample_metadata(
+        &self,
+        address: &str,
+        sample_size: usize,
+    ) -> Result<
+        SampleResponse<MetadataPackage, GetMetadataError<S::Error>>,
+        SampleError<GetMetadataError<S::Error>>,
+    > {
+        let uris = self.get_urls().await;
+        let metadata_path = format!("{}/{}", crate::METADATA_PATH, address);
+        let uris = self
+            .sample_peers(&uris, sample_size)
+            .iter()
+            .filter_map(|uri| peer_path_uri::<S::Error>(uri, &metadata_path).ok())
+            .collect();
+        let sample_request = SampleRequest {
+            request: GetMetadata,
+            uris,
+        };
+
+        let mut client = KeyserverClient::from_service(self.client.clone());
+        future::poll_fn(|context| {
+            Service::<SampleRequest<GetMetadata>>::poll_ready(&mut client, context)
+        })
+        .await?;
+        let responses = client.call(sample_request).await?;
+        Ok(SampleResponse::select(responses, select_auth_wrapper))
+    }
+
+    /// Send a request to a peer, returning a successful response.
+    async fn send(
+        &self,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, PeerRequestError<S::Error>> {
+        let mut client = self.client.clone();
+        let response_fut = async move {
+            future::poll_fn(|context| client.poll_ready(context))
+                .await
+                .map_err(PeerRequestError::Service)?;
+            client
+                .call(request)
+                .await
+                .map_err(PeerRequestError::Service)
+        };
+        let response = time::timeout(
+            Duration::from_millis(SETTINGS.peering.timeout),
+            response_fut,
+        )
+        .await
+        .map_err(|_| PeerRequestError::Timeout)??;
+        if !response.status().is_success() {
+            return Err(PeerRequestError::UnexpectedStatusCode(
+                response.status().as_u16(),
+            ));
+        }
+        Ok(response)
+    }
+
+    /// Send a request to a peer, returning the body of a successful response.
+    ///
+    /// Bodies larger than the limit, in bytes, are refused.
+    async fn request(
+        &self,
+        request: Request<Body>,
+        limit: u64,
+    ) -> Result<Bytes, PeerRequestError<S::Error>> {
+        let response = self.send(request).await?;
+        read_body(response, limit).await
+    }
+
+    /// Fetch the peers listed by a peer.
+    pub async fn get_peers(&self, uri: &Uri) -> Result<Peers, PeerRequestError<S::Error>> {
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(peers_uri(uri)?)
+            .body(Body::empty())
+            .unwrap(); // This is safe
+        let raw_peers = self.request(request, PEERS_LIMIT).await?;
+        Peers::decode(raw_peers).map_err(PeerRequestError::Decode)
+    }
+
+    /// Check a URI is a keyserver by fetching its peers.
+    pub async fn probe(&self, uri: &Uri) -> Result<(), PeerRequestError<S::Error>> {
+        self.get_peers(uri).await?;
+        Ok(())
+    }
+
+    /// Fetch the version, network and limits of a peer.
+    pub async fn get_info(&self, uri: &Uri) -> Result<KeyserverInfo, PeerRequestError<S::Error>> {
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(peer_path_uri(uri, crate::INFO_PATH)?)
+            .body(Body::empty())
+            .unwrap(); // This is safe
+        let raw_info = self.request(request, INFO_LIMIT).await?;
+        serde_json::from_slice(&raw_info).map_err(PeerRequestError::JsonDecode)
+    }
+
+    /// Check whether a peer is compatible with this keyserver.
+    ///
+    /// The compatibility of banned peers, and of peers whose info can't be fetched, such as older
+    /// keyservers, is unknown. They are kept unless `require_info` is set.
+    async fn is_compatible(&self, uri: &Uri, local_info: &KeyserverInfo) -> bool {
+        let unknown = !SETTINGS.peering.require_info;
+        if self.is_banned(uri) {
+            return unknown;
+        }
+        match self.get_info(uri).await {
+            Ok(info) => match info.check_compatible(local_info) {
+                Ok(()) => true,
+                Err(err) => {
+                    warn!(message = "skipping incompatible peer", peer = %uri, version = %info.version, reason = %err);
+                    false
+                }
+            },
+            Err(err) => {
+                debug!(message = "failed to fetch peer info", peer = %uri, error = %err);
+                unknown
+            }
+        }
+    }
+
+    /// Push metadata to a peer.
+    pub async fn push_metadata(
+        &self,
+        uri: &Uri,
+        addr_str: &str,
+        raw_auth_wrapper: Vec<u8>,
+        token: &str,
+    ) -> Result<(), PeerRequestError<S::Error>> {
+        let metadata_path = format!("{}/{}", crate::METADATA_PATH, addr_str);
+        let request = Request::builder()
+            .method(Method::PUT)
+            .uri(peer_path_uri(uri, &metadata_path)?)
+            .header(AUTHORIZATION, token)
+            .body(Body::from(raw_auth_wrapper))
+            .unwrap(); // This is safe
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Announce a URL to the seed peers.
+    pub async fn announce(&self, url: &str) {
+        let peer = Peer {
+            url: url.to_string(),
+        };
+        let mut raw_peer = Vec::with_capacity(peer.encoded_len());
+        peer.encode(&mut raw_peer).unwrap(); // This is safe
+
+        let seed_uris = SETTINGS
+            .peering
+            .peers
+            .iter()
+            .filter_map(|peer_str| parse_uri_warn(peer_str));
+        for seed_uri in seed_uris {
+            let result = match peers_uri(&seed_uri) {
+                Ok(uri) => {
+                    let request = Request::builder()
+                        .method(Method::POST)
+                        .uri(uri)
+                        .body(Body::from(raw_peer.clone()))
+                        .unwrap(); // This is safe
+                    self.request(request, net::ANNOUNCEMENT_LIMIT)
+                        .await
+                        .map(|_| ())
+                }
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(()) => info!(message = "announced to peer", peer = %seed_uri),
+                Err(err) => {
+                    warn!(message = "failed to announce to peer", peer = %seed_uri, error = %err)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CrawlError {
+    #[error("no peer could be crawled")]
+    Unreachable,
+}
+
+#[derive(Debug, Error)]
+pub enum PeerRequestError<E: fmt::Debug + fmt::Display> {
+    #[error("invalid uri: {0}")]
+    Uri(InvalidUri),
+    #[error("connection failure: {0}")]
+    Service(E),
+    #[error("request timed out")]
+    Timeout,
+    #[error("unexpected status code: {0}")]
+    UnexpectedStatusCode(u16),
+    #[error("processing body failed: {0}")]
+    Body(hyper::Error),
+    #[error("response exceeds {0} bytes")]
+    TooLarge(u64),
+    #[error("failed to decode response: {0}")]
+    Decode(prost::DecodeError),
+    #[error("failed to decode response: {0}")]
+    JsonDecode(serde_json::Error),
+}
+
+/// Read the body of a response, refusing it once it exceeds the limit, in bytes.
+async fn read_body<E: fmt::Debug + fmt::Display>(
+    response: Response<Body>,
+    limit: u64,
+) -> Result<Bytes, PeerRequestError<E>> {
+    let mut body = response.into_body();
+    let mut raw_body = BytesMut::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(PeerRequestError::Body)?;
+        if (raw_body.len() + chunk.len()) as u64 > limit {
+            return Err(PeerRequestError::TooLarge(limit));
+        }
+        raw_body.extend_from_slice(&chunk);
+    }
+    Ok(raw_body.freeze())
+}
+
+/// Construct the URI of a peer's peers endpoint.
+fn peers_uri<E: fmt::Debug + fmt::Display>(uri: &Uri) -> Result<Uri, PeerRequestError<E>> {
+    peer_path_uri(uri, crate::PEERS_PATH)
+}
+
+/// Construct the URI of a path on a peer.
+fn peer_path_uri<E: fmt::Debug + fmt::Display>(
+    uri: &Uri,
+    path: &str,
+) -> Result<Uri, PeerRequestError<E>> {
+    format!("{}/{}", uri.to_string().trim_end_matches('/'), path)
+        .parse()
+        .map_err(PeerRequestError::Uri)
 }
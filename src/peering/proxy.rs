@@ -0,0 +1,272 @@
+use std::{
+    convert::TryFrom,
+    io,
+    net::{IpAddr, SocketAddr},
+    task::{Context, Poll},
+};
+
+use futures::future::{BoxFuture, FutureExt, TryFutureExt};
+use hyper::{client::HttpConnector, Uri};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tower_service::Service;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT_COMMAND: u8 = 1;
+const SUCCEEDED: u8 = 0;
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+#[derive(Debug, Error)]
+pub enum ProxyError {
+    #[error("missing host")]
+    MissingHost,
+    #[error("host name too long")]
+    HostTooLong,
+    #[error("failed to connect to proxy: {0}")]
+    Connect(io::Error),
+    #[error("proxy handshake failed: {0}")]
+    Handshake(io::Error),
+    #[error("unexpected proxy version {0}")]
+    Version(u8),
+    #[error("proxy requires authentication")]
+    Authentication,
+    #[error("proxy refused connection with reply {0}")]
+    Refused(u8),
+    #[error("unexpected proxy address type {0}")]
+    AddressType(u8),
+}
+
+/// SOCKS5 proxy peers are reached through.
+#[derive(Clone, Copy, Debug)]
+pub struct SocksProxy {
+    pub address: SocketAddr,
+    /// Whether only `.onion` hosts are proxied, others being connected to directly.
+    pub onion_only: bool,
+}
+
+impl SocksProxy {
+    /// Whether connections to the host should go through the proxy.
+    pub fn applies_to(&self, host: &str) -> bool {
+        !self.onion_only || is_onion(host)
+    }
+}
+
+fn is_onion(host: &str) -> bool {
+    host.trim_end_matches('.')
+        .to_ascii_lowercase()
+        .ends_with(".onion")
+}
+
+/// Connector reaching peers either directly or through a SOCKS5 proxy.
+#[derive(Clone, Debug)]
+pub struct PeerConnector {
+    http: HttpConnector,
+    proxy: Option<SocksProxy>,
+}
+
+impl PeerConnector {
+    /// Construct new [`PeerConnector`].
+    pub fn new(proxy: Option<SocksProxy>) -> Self {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        Self { http, proxy }
+    }
+}
+
+impl Service<Uri> for PeerConnector {
+    type Response = TcpStream;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<TcpStream, BoxError>>;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(context).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        match self.proxy {
+            Some(proxy) if proxy.applies_to(uri.host().unwrap_or_default()) => {
+                connect_socks(proxy.address, uri).err_into().boxed()
+            }
+            _ => self.http.call(uri).err_into().boxed(),
+        }
+    }
+}
+
+/// Connect to the destination through a SOCKS5 proxy, leaving name resolution to the proxy.
+pub async fn connect_socks(proxy: SocketAddr, uri: Uri) -> Result<TcpStream, ProxyError> {
+    let host = uri
+        .host()
+        .ok_or(ProxyError::MissingHost)?
+        .trim_matches(|c| c == '[' || c == ']');
+    let port = uri.port_u16().unwrap_or_else(|| match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    });
+
+    // Construct connect request
+    let mut request = vec![SOCKS_VERSION, CONNECT_COMMAND, 0];
+    match host.parse() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ADDRESS_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ADDRESS_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let host_len = u8::try_from(host.len()).map_err(|_| ProxyError::HostTooLong)?;
+            request.push(ADDRESS_DOMAIN);
+            request.push(host_len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+
+    let mut stream = TcpStream::connect(proxy)
+        .await
+        .map_err(ProxyError::Connect)?;
+
+    // Negotiate without authentication
+    stream
+        .write_all(&[SOCKS_VERSION, 1, NO_AUTHENTICATION])
+        .await
+        .map_err(ProxyError::Handshake)?;
+    let mut method = [0; 2];
+    stream
+        .read_exact(&mut method)
+        .await
+        .map_err(ProxyError::Handshake)?;
+    if method[0] != SOCKS_VERSION {
+        return Err(ProxyError::Version(method[0]));
+    }
+    if method[1] != NO_AUTHENTICATION {
+        return Err(ProxyError::Authentication);
+    }
+
+    // Connect to destination
+    stream
+        .write_all(&request)
+        .await
+        .map_err(ProxyError::Handshake)?;
+    let mut reply = [0; 4];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .map_err(ProxyError::Handshake)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(ProxyError::Version(reply[0]));
+    }
+    if reply[1] != SUCCEEDED {
+        return Err(ProxyError::Refused(reply[1]));
+    }
+
+    // Discard the bound address and port
+    let address_len = match reply[3] {
+        ADDRESS_IPV4 => 4,
+        ADDRESS_IPV6 => 16,
+        ADDRESS_DOMAIN => stream.read_u8().await.map_err(ProxyError::Handshake)? as usize,
+        address_type => return Err(ProxyError::AddressType(address_type)),
+    };
+    let mut bound = vec![0; address_len + 2];
+    stream
+        .read_exact(&mut bound)
+        .await
+        .map_err(ProxyError::Handshake)?;
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::{body, client::Client as HttpClient, Body};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn onion_only() {
+        let proxy = SocksProxy {
+            address: "127.0.0.1:9050".parse().unwrap(),
+            onion_only: true,
+        };
+        assert!(proxy.applies_to("expyuzz4wqqyqhjn.onion"));
+        assert!(proxy.applies_to("EXPYUZZ4WQQYQHJN.ONION."));
+        assert!(!proxy.applies_to("keyserver.example.com"));
+        assert!(!proxy.applies_to("127.0.0.1"));
+
+        let proxy = SocksProxy {
+            onion_only: false,
+            ..proxy
+        };
+        assert!(proxy.applies_to("keyserver.example.com"));
+    }
+
+    #[tokio::test]
+    async fn socks_stand_in() {
+        let mut listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // Accept a single connection, recording the requested destination before serving it
+        let stand_in = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [SOCKS_VERSION, 1, NO_AUTHENTICATION]);
+            stream
+                .write_all(&[SOCKS_VERSION, NO_AUTHENTICATION])
+                .await
+                .unwrap();
+
+            let mut header = [0; 5];
+            stream.read_exact(&mut header).await.unwrap();
+            assert_eq!(
+                header[..4],
+                [SOCKS_VERSION, CONNECT_COMMAND, 0, ADDRESS_DOMAIN]
+            );
+            let mut destination = vec![0; header[4] as usize + 2];
+            stream.read_exact(&mut destination).await.unwrap();
+            stream
+                .write_all(&[SOCKS_VERSION, SUCCEEDED, 0, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(stream.read_u8().await.unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                .await
+                .unwrap();
+            (destination, request)
+        });
+
+        let connector = PeerConnector::new(Some(SocksProxy {
+            address,
+            onion_only: true,
+        }));
+        let client = HttpClient::builder().build::<_, Body>(connector);
+        let response = client
+            .get("http://expyuzz4wqqyqhjn.onion:8080/peers".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body = body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"ok");
+
+        let (destination, request) = stand_in.await.unwrap();
+        assert_eq!(&destination[..], b"expyuzz4wqqyqhjn.onion\x1f\x90");
+        assert!(request.starts_with(b"GET /peers HTTP/1.1\r\n"));
+    }
+}
@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use cashweb::keyserver_client::uniform_random_sampler;
+use hyper::Uri;
+use rand::Rng;
+use tracing::{debug, debug_span};
+
+use super::PeerHealth;
+
+/// Latency at which a peer's weight is halved.
+const REFERENCE_LATENCY: Duration = Duration::from_millis(250);
+
+/// Strategy choosing which peers to query.
+pub trait SamplingStrategy: Send + Sync {
+    /// Choose up to `size` distinct peers.
+    fn sample(&self, uris: &[Uri], size: usize) -> Vec<Uri>;
+}
+
+/// Sample peers uniformly at random.
+pub struct UniformSampling;
+
+impl SamplingStrategy for UniformSampling {
+    fn sample(&self, uris: &[Uri], size: usize) -> Vec<Uri> {
+        let span = debug_span!(
+            "sample_peers",
+            strategy = "uniform",
+            candidates = uris.len(),
+            size
+        );
+        let _enter = span.enter();
+
+        let sample = uniform_random_sampler(uris, size);
+        for uri in &sample {
+            debug!(message = "sampled peer", peer = %uri);
+        }
+        sample
+    }
+}
+
+/// Sample peers with probability proportional to their weight, favouring fast and reliable peers.
+pub struct WeightedSampling {
+    health: PeerHealth,
+}
+
+impl WeightedSampling {
+    /// Construct new [`WeightedSampling`].
+    pub fn new(health: PeerHealth) -> Self {
+        Self { health }
+    }
+
+    /// Weight of a peer, the estimated success rate discounted by the average latency.
+    ///
+    /// Peers without history are assumed to succeed half the time at the reference latency.
+    pub fn weight(&self, uri: &Uri) -> f64 {
+        let stats = self.health.get_stats(uri).unwrap_or_default();
+        let success_rate = (stats.successes as f64 + 1.)
+            / ((stats.successes + stats.failures + stats.invalid) as f64 + 2.);
+        let latency = stats.latency.unwrap_or(REFERENCE_LATENCY);
+        success_rate / (1. + latency.as_secs_f64() / REFERENCE_LATENCY.as_secs_f64())
+    }
+}
+
+impl SamplingStrategy for WeightedSampling {
+    fn sample(&self, uris: &[Uri], size: usize) -> Vec<Uri> {
+        let span = debug_span!(
+            "sample_peers",
+            strategy = "weighted",
+            candidates = uris.len(),
+            size
+        );
+        let _enter = span.enter();
+
+        // Weighted sampling without replacement, keeping the largest keys of u^(1 / weight)
+        let mut rng = rand::thread_rng();
+        let mut keyed: Vec<(f64, f64, &Uri)> = uris
+            .iter()
+            .map(|uri| {
+                let weight = self.weight(uri);
+                let key = rng.gen::<f64>().powf(1. / weight);
+                (key, weight, uri)
+            })
+            .collect();
+        keyed.sort_by(|(key_a, _, _), (key_b, _, _)| key_b.partial_cmp(key_a).unwrap());
+        keyed
+            .into_iter()
+            .take(size)
+            .map(|(_, weight, uri)| {
+                debug!(message = "sampled peer", peer = %uri, weight = %weight);
+                uri.clone()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted_sampling() {
+        let health = PeerHealth::default();
+        let fast: Uri = "http://fast".parse().unwrap();
+        let slow: Uri = "http://slow".parse().unwrap();
+        let flaky: Uri = "http://flaky".parse().unwrap();
+        for _ in 0..10 {
+            health.record_success(&fast, Duration::from_millis(50));
+            health.record_success(&slow, Duration::from_secs(2));
+            health.record_failure(&flaky);
+        }
+
+        let sampling = WeightedSampling::new(health);
+        let unknown: Uri = "http://unknown".parse().unwrap();
+        assert!(sampling.weight(&fast) > sampling.weight(&unknown));
+        assert!(sampling.weight(&unknown) > sampling.weight(&slow));
+        assert!(sampling.weight(&unknown) > sampling.weight(&flaky));
+
+        let uris = vec![fast.clone(), slow, flaky, unknown];
+        let sample = sampling.sample(&uris, 2);
+        assert_eq!(sample.len(), 2);
+        assert_ne!(sample[0], sample[1]);
+        assert_eq!(sampling.sample(&uris, 8).len(), 4);
+    }
+}
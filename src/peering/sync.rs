@@ -0,0 +1,425 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use bitcoincash_addr::Address;
+use hyper::{header::AUTHORIZATION, Body, Method, Request, Response, Uri};
+use prost::Message as _;
+use ring::digest::{digest, Context, SHA256};
+use rocksdb::Error as RocksError;
+use thiserror::Error;
+use tokio::{sync::Mutex, task};
+use tower_service::Service;
+use tracing::warn;
+
+use super::{peer_path_uri, read_body, PeerHandler, PeerRequestError, TokenCache};
+use crate::{
+    db::Database,
+    models::{
+        keyserver::AddressMetadata,
+        sync::{Bucket, BucketDigests, BucketEntry},
+        wrapper::AuthWrapper,
+    },
+    net::{self, Authorization, PutMetadataError, HEADER_VALUE_FALSE, SAMPLING},
+    node::{Node, NodeError},
+    token::{TokenScheme, ValidationError},
+    BUCKETS_PATH, DIGESTS_PATH, METADATA_PATH, SETTINGS, SYNC_PATH,
+};
+
+/// Number of address buckets, each keyed by the first byte of the address.
+pub const SYNC_BUCKETS: usize = 256;
+
+/// Maximum size of the bucket digests served by a peer, each digest taking 34 bytes encoded.
+const DIGESTS_LIMIT: u64 = SYNC_BUCKETS as u64 * 34;
+
+/// Maximum size of an address bucket served by a peer.
+const BUCKET_LIMIT: u64 = 16_000_000;
+
+/// Collect the entries of an address bucket, excluding records which can't be transferred.
+pub fn bucket_entries(database: &Database, bucket: u8) -> Bucket {
+    let entries = database
+        .get_metadata_bucket(bucket)
+        .into_iter()
+        .filter(|(_, wrapper)| !wrapper.invalid && !wrapper.operator_published)
+        .map(|(address, wrapper)| BucketEntry {
+            address,
+            digest: digest(&SHA256, &wrapper.serialized_auth_wrapper)
+                .as_ref()
+                .to_vec(),
+        })
+        .collect();
+    Bucket { entries }
+}
+
+/// Digest the entries of an address bucket.
+pub fn bucket_digest(bucket: &Bucket) -> Vec<u8> {
+    let mut context = Context::new(&SHA256);
+    for entry in &bucket.entries {
+        context.update(&entry.address);
+        context.update(&entry.digest);
+    }
+    context.finish().as_ref().to_vec()
+}
+
+/// Digest every address bucket.
+pub fn bucket_digests(database: &Database) -> BucketDigests {
+    let digests = (0..SYNC_BUCKETS)
+        .map(|bucket| bucket_digest(&bucket_entries(database, bucket as u8)))
+        .collect();
+    BucketDigests { digests }
+}
+
+/// Bucket digests served to peers, computed at most once per block.
+#[derive(Clone)]
+pub struct DigestCache {
+    database: Database,
+    digests: Arc<Mutex<Option<BucketDigests>>>,
+}
+
+impl DigestCache {
+    /// Construct new [`DigestCache`] over the metadata in the database.
+    pub fn new(database: Database) -> Self {
+        Self {
+            database,
+            digests: Default::default(),
+        }
+    }
+
+    /// Get the bucket digests, computing them if they were cleared since.
+    pub async fn get_digests(&self) -> BucketDigests {
+        let mut digests = self.digests.lock().await;
+        if let Some(some) = digests.as_ref() {
+            return some.clone();
+        }
+        let database = self.database.clone();
+        let computed = task::spawn_blocking(move || bucket_digests(&database))
+            .await
+            .unwrap();
+        *digests = Some(computed.clone());
+        computed
+    }
+
+    /// Clear the bucket digests, so that they are recomputed on the next request.
+    pub async fn clear(&self) {
+        *self.digests.lock().await = None;
+    }
+}
+
+/// Order metadata by timestamp, breaking ties by digest so that peers converge.
+fn metadata_version(raw_auth_wrapper: &[u8]) -> (i64, Vec<u8>) {
+    let timestamp = AuthWrapper::decode(raw_auth_wrapper)
+        .ok()
+        .and_then(|auth_wrapper| AddressMetadata::decode(&auth_wrapper.payload[..]).ok())
+        .map(|metadata| metadata.timestamp)
+        .unwrap_or_default();
+    (
+        timestamp,
+        digest(&SHA256, raw_auth_wrapper).as_ref().to_vec(),
+    )
+}
+
+#[derive(Debug, Error)]
+pub enum SyncError<E: fmt::Debug + fmt::Display> {
+    #[error("{0}")]
+    Request(PeerRequestError<E>),
+    #[error("unexpected number of bucket digests: {0}")]
+    DigestCount(usize),
+}
+
+impl<E: fmt::Debug + fmt::Display> From<PeerRequestError<E>> for SyncError<E> {
+    fn from(err: PeerRequestError<E>) -> Self {
+        Self::Request(err)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SyncRecordError<E: fmt::Debug + fmt::Display> {
+    #[error("{0}")]
+    Request(PeerRequestError<E>),
+    #[error("metadata served without a token")]
+    MissingToken,
+    #[error("metadata exceeds size limit")]
+    TooLarge,
+    #[error("failed to decode authorization wrapper: {0}")]
+    Decode(prost::DecodeError),
+    #[error("token validation failed: {0}")]
+    Validation(ValidationError),
+    #[error("failed to get token confirmations: {0}")]
+    Node(NodeError),
+    #[error("token requires {0} more confirmations")]
+    Unconfirmed(u32),
+    #[error("failed to read from database: {0}")]
+    Database(RocksError),
+    #[error(transparent)]
+    Put(PutMetadataError),
+}
+
+impl<E: fmt::Debug + fmt::Display> SyncRecordError<E> {
+    /// Whether the peer is at fault for serving the record.
+    fn is_invalid(&self) -> bool {
+        match self {
+            Self::MissingToken | Self::TooLarge | Self::Decode(_) => true,
+            Self::Validation(ValidationError::Node(_)) => false,
+            Self::Validation(_) => true,
+            Self::Put(PutMetadataError::Database(_)) => false,
+            Self::Put(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> From<PeerRequestError<E>> for SyncRecordError<E> {
+    fn from(err: PeerRequestError<E>) -> Self {
+        Self::Request(err)
+    }
+}
+
+impl<S> PeerHandler<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S: Send + Clone + 'static,
+    S::Future: Send,
+    S::Error: fmt::Debug + Send + fmt::Display,
+{
+    /// Reconcile metadata with a peer, returning the number of records stored.
+    ///
+    /// Bucket digests are compared and only the records within differing buckets are fetched.
+    pub async fn sync_metadata<N: Node>(
+        &self,
+        uri: &Uri,
+        database: &Database,
+        token_scheme: &Arc<dyn TokenScheme>,
+        node: &N,
+        token_cache: &TokenCache,
+    ) -> Result<usize, SyncError<S::Error>> {
+        // Compare bucket digests
+        let digests_path = format!("{}/{}", SYNC_PATH, DIGESTS_PATH);
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(peer_path_uri(uri, &digests_path)?)
+            .body(Body::empty())
+            .unwrap(); // This is safe
+        let raw_digests = self
+            .request(request, DIGESTS_LIMIT)
+            .await
+            .map_err(|err| self.record_oversized(uri, err))?;
+        let remote_digests = match BucketDigests::decode(raw_digests) {
+            Ok(some) if some.digests.len() == SYNC_BUCKETS => some,
+            Ok(some) => {
+                self.health.record_invalid(uri);
+                return Err(SyncError::DigestCount(some.digests.len()));
+            }
+            Err(err) => {
+                self.health.record_invalid(uri);
+                return Err(PeerRequestError::Decode(err).into());
+            }
+        };
+        let database_inner = database.clone();
+        let local_digests = task::spawn_blocking(move || bucket_digests(&database_inner))
+            .await
+            .unwrap();
+
+        let mut stored = 0;
+        let differing = remote_digests
+            .digests
+            .iter()
+            .zip(&local_digests.digests)
+            .enumerate()
+            .filter(|(_, (remote, local))| remote != local)
+            .map(|(index, _)| index as u8);
+        for bucket in differing {
+            // Fetch the peer's entries
+            let bucket_path = format!("{}/{}/{}", SYNC_PATH, BUCKETS_PATH, bucket);
+            let request = Request::builder()
+                .method(Method::GET)
+                .uri(peer_path_uri(uri, &bucket_path)?)
+                .body(Body::empty())
+                .unwrap(); // This is safe
+            let raw_bucket = self
+                .request(request, BUCKET_LIMIT)
+                .await
+                .map_err(|err| self.record_oversized(uri, err))?;
+            let remote_bucket = Bucket::decode(raw_bucket).map_err(|err| {
+                self.health.record_invalid(uri);
+                PeerRequestError::Decode(err)
+            })?;
+            let database_inner = database.clone();
+            let local_entries: HashMap<Vec<u8>, Vec<u8>> =
+                task::spawn_blocking(move || bucket_entries(&database_inner, bucket))
+                    .await
+                    .unwrap()
+                    .entries
+                    .into_iter()
+                    .map(|entry| (entry.address, entry.digest))
+                    .collect();
+
+            // Transfer records which differ
+            for entry in remote_bucket.entries {
+                if entry.address.first() != Some(&bucket)
+                    || local_entries.get(&entry.address) == Some(&entry.digest)
+                {
+                    continue;
+                }
+                match self
+                    .sync_record(
+                        uri,
+                        entry.address,
+                        database,
+                        token_scheme,
+                        node,
+                        token_cache,
+                    )
+                    .await
+                {
+                    Ok(true) => stored += 1,
+                    Ok(false) => (),
+                    Err(err) => {
+                        if err.is_invalid() {
+                            self.health.record_invalid(uri);
+                        }
+                        warn!(message = "failed to sync metadata", peer = %uri, error = %err);
+                    }
+                }
+            }
+        }
+        Ok(stored)
+    }
+
+    /// Penalize a peer which served an oversized response.
+    fn record_oversized(
+        &self,
+        uri: &Uri,
+        err: PeerRequestError<S::Error>,
+    ) -> PeerRequestError<S::Error> {
+        if let PeerRequestError::TooLarge(_) = err {
+            self.health.record_invalid(uri);
+        }
+        err
+    }
+
+    /// Fetch and verify a peer's record, storing it if it's newer than the local one.
+    async fn sync_record<N: Node>(
+        &self,
+        uri: &Uri,
+        address: Vec<u8>,
+        database: &Database,
+        token_scheme: &Arc<dyn TokenScheme>,
+        node: &N,
+        token_cache: &TokenCache,
+    ) -> Result<bool, SyncRecordError<S::Error>> {
+        // Fetch metadata, preventing the peer from sampling its own peers
+        let addr = Address {
+            body: address,
+            ..Default::default()
+        };
+        let metadata_path = format!("{}/{}", METADATA_PATH, addr.encode().unwrap());
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(peer_path_uri(uri, &metadata_path)?)
+            .header(SAMPLING, HEADER_VALUE_FALSE)
+            .body(Body::empty())
+            .unwrap(); // This is safe
+        let response = self.send(request).await?;
+        let token = response
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("POP "))
+            .ok_or(SyncRecordError::MissingToken)?
+            .to_string();
+        let raw_auth_wrapper = read_body(response, SETTINGS.limits.metadata_size)
+            .await
+            .map_err(|err| match err {
+                PeerRequestError::TooLarge(_) => SyncRecordError::TooLarge,
+                err => err.into(),
+            })?;
+        let auth_wrapper =
+            AuthWrapper::decode(raw_auth_wrapper.clone()).map_err(SyncRecordError::Decode)?;
+
+        // Validate token
+        let metadata_hash = if auth_wrapper.payload_digest.len() == 32 {
+            auth_wrapper.payload_digest.clone()
+        } else {
+            digest(&SHA256, &auth_wrapper.payload).as_ref().to_vec()
+        };
+        let pub_key_hash = digest(&SHA256, &auth_wrapper.public_key);
+        let raw_token = token_scheme
+            .validate_token(
+                pub_key_hash.as_ref(),
+                &metadata_hash,
+                &token,
+                raw_auth_wrapper.len(),
+            )
+            .await
+            .map_err(SyncRecordError::Validation)?;
+
+        // Check the commitment transaction has enough confirmations
+        let min_confirmations = SETTINGS.payments.min_confirmations;
+        if let Some(tx_id) = token_scheme.commitment_tx_id(&raw_token) {
+            if min_confirmations > 0 {
+                let confirmations = node
+                    .get_confirmations(tx_id)
+                    .await
+                    .map_err(SyncRecordError::Node)?;
+                let remaining = min_confirmations.saturating_sub(confirmations);
+                if remaining > 0 {
+                    return Err(SyncRecordError::Unconfirmed(remaining));
+                }
+            }
+        }
+
+        // Keep the most recent metadata, never replacing metadata published by the operator
+        let database_inner = database.clone();
+        let addr_raw = addr.as_body().to_vec();
+        let local_opt = task::spawn_blocking(move || database_inner.get_metadata(&addr_raw))
+            .await
+            .unwrap()
+            .map_err(SyncRecordError::Database)?;
+        if let Some(local) = local_opt.filter(|wrapper| !wrapper.invalid) {
+            if local.operator_published
+                || metadata_version(&raw_auth_wrapper)
+                    <= metadata_version(&local.serialized_auth_wrapper)
+            {
+                return Ok(false);
+            }
+        }
+
+        // Put to database, verifying signatures
+        net::put_metadata(
+            addr,
+            raw_auth_wrapper,
+            auth_wrapper,
+            Authorization::Token(raw_token),
+            database.clone(),
+            token_cache.clone(),
+        )
+        .await
+        .map_err(SyncRecordError::Put)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_digest_covers_entries() {
+        let entry = |address: u8, digest: u8| BucketEntry {
+            address: vec![address; 20],
+            digest: vec![digest; 32],
+        };
+        let bucket = Bucket {
+            entries: vec![entry(1, 1), entry(1, 2)],
+        };
+        let changed = Bucket {
+            entries: vec![entry(1, 1), entry(1, 3)],
+        };
+        let reordered = Bucket {
+            entries: vec![entry(1, 2), entry(1, 1)],
+        };
+
+        assert_eq!(bucket_digest(&bucket), bucket_digest(&bucket.clone()));
+        assert_ne!(bucket_digest(&bucket), bucket_digest(&changed));
+        assert_ne!(bucket_digest(&bucket), bucket_digest(&reordered));
+        assert_ne!(bucket_digest(&bucket), bucket_digest(&Bucket::default()));
+    }
+}
@@ -0,0 +1,543 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures::{
+    future::{self, BoxFuture, FutureExt, TryFutureExt},
+    ready,
+};
+use hex::FromHexError;
+use hyper::{
+    client::connect::{Connected, Connection},
+    http::uri::InvalidUri,
+    Uri,
+};
+use hyper_tls::{HttpsConnector, MaybeHttpsStream};
+use native_tls::{Certificate, HandshakeError, Identity, TlsConnector, TlsStream};
+use ring::digest::{digest, SHA256};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tower_service::Service;
+
+use super::PeerConnector;
+use crate::settings::PeerTls;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Size of the reads from the underlying connection of a pinned peer.
+const READ_SIZE: usize = 8 * 1024;
+
+/// SHA-256 digest of a DER encoded certificate.
+pub type Fingerprint = [u8; 32];
+
+/// Parse a hex encoded fingerprint, optionally separated by colons.
+pub fn parse_fingerprint(fingerprint: &str) -> Result<Fingerprint, FromHexError> {
+    let mut digest = [0; 32];
+    hex::decode_to_slice(fingerprint.trim().replace(':', ""), &mut digest)?;
+    Ok(digest)
+}
+
+/// Port of a URI, defaulting by scheme.
+fn uri_port(uri: &Uri) -> u16 {
+    uri.port_u16().unwrap_or_else(|| match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    })
+}
+
+#[derive(Debug, Error)]
+pub enum TlsConfigError {
+    #[error("failed to read {0}: {1}")]
+    Read(String, io::Error),
+    #[error("failed to load certificate: {0}")]
+    Tls(native_tls::Error),
+    #[error("invalid pinned peer url: {0}")]
+    Uri(InvalidUri),
+    #[error("pinned peer url has no host")]
+    MissingHost,
+    #[error("pinned peer url must use https")]
+    Scheme,
+    #[error("invalid fingerprint: {0}")]
+    Fingerprint(FromHexError),
+}
+
+#[derive(Debug, Error)]
+pub enum PinError {
+    #[error("pinned peer {0} must be reached over https on port {1}")]
+    Endpoint(String, u16),
+    #[error("pinned peer {0} presented no certificate")]
+    MissingCertificate(String),
+    #[error("pinned peer {0} presented an unexpected certificate")]
+    Mismatch(String),
+}
+
+/// Load a PEM encoded certificate.
+pub fn load_certificate(path: &str) -> Result<Certificate, TlsConfigError> {
+    let pem = fs::read(path).map_err(|err| TlsConfigError::Read(path.to_string(), err))?;
+    Certificate::from_pem(&pem).map_err(TlsConfigError::Tls)
+}
+
+/// Fingerprint of a certificate.
+pub fn certificate_fingerprint(certificate: &Certificate) -> Result<Fingerprint, TlsConfigError> {
+    let der = certificate.to_der().map_err(TlsConfigError::Tls)?;
+    let mut fingerprint = [0; 32];
+    fingerprint.copy_from_slice(digest(&SHA256, &der).as_ref());
+    Ok(fingerprint)
+}
+
+/// Endpoint and leaf certificate fingerprint a pinned peer must present.
+#[derive(Clone, Debug)]
+struct PinnedPeerCertificate {
+    port: u16,
+    fingerprint: Fingerprint,
+}
+
+/// Connector securing peer connections with TLS, presenting a client certificate if configured.
+///
+/// Pinned peers are matched by host, reached over https on their configured port only, and must
+/// present exactly their pinned leaf certificate.
+#[derive(Clone)]
+pub struct PeerTlsConnector {
+    inner: PeerConnector,
+    https: HttpsConnector<PeerConnector>,
+    pinned_tls: TlsConnector,
+    pinned: Arc<HashMap<String, PinnedPeerCertificate>>,
+}
+
+impl PeerTlsConnector {
+    /// Construct new [`PeerTlsConnector`].
+    pub fn new(inner: PeerConnector, settings: &PeerTls) -> Result<Self, TlsConfigError> {
+        let mut builder = TlsConnector::builder();
+        let mut pinned_builder = TlsConnector::builder();
+        if let Some(path) = &settings.identity {
+            let der = fs::read(path).map_err(|err| TlsConfigError::Read(path.clone(), err))?;
+            let identity = Identity::from_pkcs12(&der, &settings.identity_password)
+                .map_err(TlsConfigError::Tls)?;
+            builder.identity(identity.clone());
+            pinned_builder.identity(identity);
+        }
+        let tls = builder.build().map_err(TlsConfigError::Tls)?;
+        let https = HttpsConnector::from((inner.clone(), tls.into()));
+
+        // Pinned peers are authenticated by the fingerprint of their certificate alone
+        let pinned_tls = pinned_builder
+            .danger_accept_invalid_certs(true)
+            .build()
+            .map_err(TlsConfigError::Tls)?;
+
+        let mut pinned = HashMap::new();
+        for pinned_peer in &settings.pinned_peers {
+            let uri: Uri = pinned_peer.url.parse().map_err(TlsConfigError::Uri)?;
+            if uri.scheme_str() != Some("https") {
+                return Err(TlsConfigError::Scheme);
+            }
+            let host = uri.host().ok_or(TlsConfigError::MissingHost)?;
+            let certificate = load_certificate(&pinned_peer.certificate)?;
+            let pin = PinnedPeerCertificate {
+                port: uri_port(&uri),
+                fingerprint: certificate_fingerprint(&certificate)?,
+            };
+            pinned.insert(host.to_ascii_lowercase(), pin);
+        }
+
+        Ok(Self {
+            inner,
+            https,
+            pinned_tls,
+            pinned: Arc::new(pinned),
+        })
+    }
+}
+
+impl Service<Uri> for PeerTlsConnector {
+    type Response = PeerStream;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<PeerStream, BoxError>>;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.https.poll_ready(context)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let host = uri.host().unwrap_or_default().to_ascii_lowercase();
+        let pin = match self.pinned.get(&host) {
+            Some(some) => some.clone(),
+            None => return self.https.call(uri).map_ok(PeerStream::Https).boxed(),
+        };
+
+        // Refuse to reach pinned peers other than over https on their configured port
+        if uri.scheme_str() != Some("https") || uri_port(&uri) != pin.port {
+            return future::err(PinError::Endpoint(host, pin.port).into()).boxed();
+        }
+        let tcp = self.inner.call(uri);
+        let pinned_tls = self.pinned_tls.clone();
+        async move {
+            let tcp = tcp.await?;
+            let stream = PinnedTlsStream::connect(&pinned_tls, &host, tcp).await?;
+            let certificate = stream
+                .tls
+                .peer_certificate()?
+                .ok_or_else(|| PinError::MissingCertificate(host.clone()))?;
+            if certificate_fingerprint(&certificate)? != pin.fingerprint {
+                return Err(PinError::Mismatch(host).into());
+            }
+            Ok(PeerStream::Pinned(Box::new(stream)))
+        }
+        .boxed()
+    }
+}
+
+/// Connection to a peer, pinned connections being secured by [`PinnedTlsStream`].
+pub enum PeerStream {
+    Https(MaybeHttpsStream<TcpStream>),
+    Pinned(Box<PinnedTlsStream>),
+}
+
+impl Connection for PeerStream {
+    fn connected(&self) -> Connected {
+        match self {
+            Self::Https(stream) => stream.connected(),
+            Self::Pinned(_) => Connected::new(),
+        }
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Https(stream) => Pin::new(stream).poll_read(context, buf),
+            Self::Pinned(stream) => Pin::new(stream.as_mut()).poll_read(context, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Https(stream) => Pin::new(stream).poll_write(context, buf),
+            Self::Pinned(stream) => Pin::new(stream.as_mut()).poll_write(context, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Https(stream) => Pin::new(stream).poll_flush(context),
+            Self::Pinned(stream) => Pin::new(stream.as_mut()).poll_flush(context),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Https(stream) => Pin::new(stream).poll_shutdown(context),
+            Self::Pinned(stream) => Pin::new(stream.as_mut()).poll_shutdown(context),
+        }
+    }
+}
+
+/// Buffers TLS records exchanged with the underlying connection, so that the TLS session can be
+/// driven without blocking.
+#[derive(Default)]
+struct Records {
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+    eof: bool,
+}
+
+impl Read for Records {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.incoming.is_empty() {
+            if self.eof {
+                return Ok(0);
+            }
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(self.incoming.len());
+        buf[..len].copy_from_slice(&self.incoming[..len]);
+        self.incoming.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for Records {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// TLS connection to a pinned peer, exposing its certificate once established.
+pub struct PinnedTlsStream {
+    tcp: TcpStream,
+    tls: TlsStream<Records>,
+}
+
+impl PinnedTlsStream {
+    /// Perform the TLS handshake, without verifying the certificate presented.
+    async fn connect(
+        connector: &TlsConnector,
+        domain: &str,
+        mut tcp: TcpStream,
+    ) -> io::Result<Self> {
+        let mut handshake = connector.connect(domain, Records::default());
+        loop {
+            match handshake {
+                Ok(tls) => {
+                    let mut stream = Self { tcp, tls };
+                    future::poll_fn(|context| stream.poll_send(context)).await?;
+                    return Ok(stream);
+                }
+                Err(HandshakeError::Failure(err)) => return Err(io::Error::other(err)),
+                Err(HandshakeError::WouldBlock(mut mid_handshake)) => {
+                    let records = mid_handshake.get_mut();
+                    tcp.write_all(&records.outgoing).await?;
+                    records.outgoing.clear();
+                    let mut buf = [0; READ_SIZE];
+                    match tcp.read(&mut buf).await? {
+                        0 => records.eof = true,
+                        len => records.incoming.extend_from_slice(&buf[..len]),
+                    }
+                    handshake = mid_handshake.handshake();
+                }
+            }
+        }
+    }
+
+    /// Write the buffered outgoing records to the connection.
+    fn poll_send(&mut self, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let records = self.tls.get_mut();
+        while !records.outgoing.is_empty() {
+            let len = ready!(Pin::new(&mut self.tcp).poll_write(context, &records.outgoing))?;
+            if len == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            records.outgoing.drain(..len);
+        }
+        Pin::new(&mut self.tcp).poll_flush(context)
+    }
+
+    /// Read incoming records from the connection.
+    fn poll_receive(&mut self, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut buf = [0; READ_SIZE];
+        let len = ready!(Pin::new(&mut self.tcp).poll_read(context, &mut buf))?;
+        let records = self.tls.get_mut();
+        if len == 0 {
+            records.eof = true;
+        } else {
+            records.incoming.extend_from_slice(&buf[..len]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for PinnedTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            match this.tls.read(buf) {
+                Ok(len) => return Poll::Ready(Ok(len)),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(this.poll_send(context))?;
+                    ready!(this.poll_receive(context))?;
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for PinnedTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(context))?;
+        loop {
+            match this.tls.write(buf) {
+                Ok(len) => {
+                    // Records left unsent are written on the next write or flush
+                    if let Poll::Ready(Err(err)) = this.poll_send(context) {
+                        return Poll::Ready(Err(err));
+                    }
+                    return Poll::Ready(Ok(len));
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    ready!(this.poll_receive(context))?;
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_send(context)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.tls.shutdown() {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+            Err(err) => return Poll::Ready(Err(err)),
+        }
+        ready!(this.poll_send(context))?;
+        Pin::new(&mut this.tcp).poll_shutdown(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{net::TcpListener as StdTcpListener, thread};
+
+    use native_tls::TlsAcceptor;
+
+    use crate::settings::PinnedPeer;
+
+    const PARTNER_CERTIFICATE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/partner.crt");
+    const PARTNER_IDENTITY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/partner.p12");
+    const PARTNER_FINGERPRINT: &str =
+        "34187494724b15621c394fd0e1c3e10bf3ac7ba46eea5367bb65d1d5b66ee893";
+
+    fn settings(url: &str) -> PeerTls {
+        PeerTls {
+            identity: None,
+            identity_password: String::new(),
+            pinned_peers: vec![PinnedPeer {
+                url: url.to_string(),
+                certificate: PARTNER_CERTIFICATE.to_string(),
+            }],
+            require_client_cert: false,
+            client_cert_header: "X-Client-Cert-Fingerprint".to_string(),
+            client_fingerprints: vec![],
+        }
+    }
+
+    /// Serve a single TLS connection using the partner identity, echoing a line back.
+    fn serve_partner() -> u16 {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let der = fs::read(PARTNER_IDENTITY).unwrap();
+        let identity = Identity::from_pkcs12(&der, "partner").unwrap();
+        let acceptor = TlsAcceptor::new(identity).unwrap();
+        thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            if let Ok(mut tls) = acceptor.accept(tcp) {
+                let mut buf = [0; 5];
+                if tls.read_exact(&mut buf).is_ok() {
+                    tls.write_all(&buf).unwrap();
+                }
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn fingerprints() {
+        let fingerprint = [0xab; 32];
+        assert_eq!(
+            parse_fingerprint(&hex::encode(fingerprint)).unwrap(),
+            fingerprint
+        );
+        let separated = vec!["AB"; 32].join(":");
+        assert_eq!(parse_fingerprint(&separated).unwrap(), fingerprint);
+        assert!(parse_fingerprint("abcd").is_err());
+
+        let certificate = load_certificate(PARTNER_CERTIFICATE).unwrap();
+        assert_eq!(
+            hex::encode(certificate_fingerprint(&certificate).unwrap()),
+            PARTNER_FINGERPRINT
+        );
+    }
+
+    #[tokio::test]
+    async fn pinned_endpoints() {
+        let mut connector = PeerTlsConnector::new(
+            PeerConnector::new(None),
+            &settings("https://Partner.example"),
+        )
+        .unwrap();
+        assert_eq!(connector.pinned["partner.example"].port, 443);
+
+        // Pinned hosts are only reached over https on the configured port
+        for url in &[
+            "http://partner.example/peers",
+            "https://partner.example:8443/peers",
+        ] {
+            let err = connector.call(url.parse().unwrap()).await.err().unwrap();
+            assert!(err.downcast_ref::<PinError>().is_some());
+        }
+
+        assert!(matches!(
+            PeerTlsConnector::new(
+                PeerConnector::new(None),
+                &settings("http://partner.example")
+            ),
+            Err(TlsConfigError::Scheme)
+        ));
+        let settings = PeerTls {
+            identity: Some("missing.p12".to_string()),
+            ..settings("https://partner.example")
+        };
+        assert!(matches!(
+            PeerTlsConnector::new(PeerConnector::new(None), &settings),
+            Err(TlsConfigError::Read(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn pinned_certificates() {
+        // The pinned certificate is accepted regardless of host name or issuer
+        let port = serve_partner();
+        let url = format!("https://localhost:{}", port);
+        let mut connector =
+            PeerTlsConnector::new(PeerConnector::new(None), &settings(&url)).unwrap();
+        let mut stream = connector.call(url.parse().unwrap()).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Any other certificate is rejected
+        let port = serve_partner();
+        let url = format!("https://localhost:{}", port);
+        let mut connector =
+            PeerTlsConnector::new(PeerConnector::new(None), &settings(&url)).unwrap();
+        Arc::get_mut(&mut connector.pinned)
+            .unwrap()
+            .get_mut("localhost")
+            .unwrap()
+            .fingerprint = [0; 32];
+        let err = connector.call(url.parse().unwrap()).await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<PinError>(),
+            Some(PinError::Mismatch(_))
+        ));
+    }
+}
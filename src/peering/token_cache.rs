@@ -1,76 +1,200 @@
-use std::{collections::VecDeque, fmt, sync::Arc};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
 
 use bitcoincash_addr::Address;
-use dashmap::DashSet;
-use hyper::{Body, Request, Response};
-use tokio::sync::RwLock;
+use futures::future;
+use hyper::{Body, Request, Response, Uri};
+use rocksdb::Error as RocksError;
+use tokio::{sync::Mutex, task};
 use tower_service::Service;
+use tracing::{error, warn};
 
 use super::PeerHandler;
-use crate::{db::Database, SETTINGS};
+use crate::{db::Database, models::database::BroadcastRecord, SETTINGS};
 
+/// Maximum number of blocks between attempts to broadcast metadata.
+const MAX_BACKOFF: u32 = 144;
+
+/// Number of attempts after which metadata is dropped from the queue.
+const MAX_ATTEMPTS: u32 = 8;
+
+/// Queue of metadata awaiting broadcast to peers, persisted in the database.
 #[derive(Clone)]
 pub struct TokenCache {
-    tokens_blocks: Arc<RwLock<VecDeque<DashSet<Address>>>>,
+    database: Database,
+    height: Arc<AtomicU32>,
+    lock: Arc<Mutex<()>>,
 }
 
-impl Default for TokenCache {
-    fn default() -> Self {
-        let deque = VecDeque::from(vec![Default::default(); SETTINGS.peering.broadcast_delay]);
+impl TokenCache {
+    /// Construct new [`TokenCache`] over the queue persisted in the database.
+    ///
+    /// The queue is scanned on each block, so broadcasts interrupted by a restart are resumed then.
+    pub fn new(database: Database) -> Self {
         Self {
-            tokens_blocks: Arc::new(RwLock::new(deque)),
+            database,
+            height: Default::default(),
+            lock: Default::default(),
         }
     }
-}
 
-impl TokenCache {
-    pub async fn add_token(&self, addr: Address) {
-        let token_blocks = self.tokens_blocks.read().await;
-        // TODO: Check previous blocks?
-        // TODO: Check consistency garauntees of the dashmap under iter + insert
-        token_blocks.front().unwrap().insert(addr); // TODO: Double check this is safe
+    /// Set the current block height.
+    pub fn set_height(&self, height: u32) {
+        self.height.store(height, Ordering::SeqCst);
+    }
+
+    /// Queue metadata for broadcast after `broadcast_delay` blocks, restarting any pending broadcast.
+    pub async fn add_token(&self, addr: Address) -> Result<(), RocksError> {
+        // Standalone nodes don't broadcast
+        if !SETTINGS.peering.enabled {
+            return Ok(());
+        }
+
+        let broadcast_record = BroadcastRecord {
+            target_height: self.height.load(Ordering::SeqCst)
+                + SETTINGS.peering.broadcast_delay as u32,
+            peers: Vec::new(),
+            attempts: 0,
+        };
+        let database = self.database.clone();
+        let _guard = self.lock.lock().await;
+        task::spawn_blocking(move || database.put_broadcast(addr.as_body(), &broadcast_record))
+            .await
+            .unwrap()
     }
 
-    pub async fn broadcast_block<S>(&self, peer_handler: &PeerHandler<S>, db: &Database)
+    /// Broadcast the metadata due at the given block height.
+    ///
+    /// Metadata is removed from the queue once enough of the known peers accept it, otherwise the
+    /// remaining peers are retried with exponential backoff, up to `MAX_ATTEMPTS` times.
+    pub async fn broadcast_block<S>(&self, peer_handler: &PeerHandler<S>, height: u32)
     where
         S: Service<Request<Body>, Response = Response<Body>>,
         S: Send + Clone + 'static,
         <S as Service<Request<Body>>>::Future: Send,
         S::Error: Send + fmt::Debug + fmt::Display,
     {
-        let mut token_blocks = self.tokens_blocks.write().await;
+        self.set_height(height);
 
-        // Cycle blocks
-        token_blocks.push_front(Default::default());
-        let token_block = match token_blocks.pop_back() {
-            Some(some) => some,
-            None => return,
-        };
+        // Standalone nodes don't broadcast
+        if !SETTINGS.peering.enabled {
+            return;
+        }
 
-        // Broadcast each metadata
-        for addr in token_block.into_iter() {
-            let db_wrapper = match db.get_metadata(addr.as_body()) {
-                Ok(Some(some)) => some,
-                _ => continue,
-            };
-            let addr_str = addr.encode().unwrap(); // This is safe
-
-            // Reconstruct token
-            let raw_token = db_wrapper.token;
-            let url_safe_config = base64::Config::new(base64::CharacterSet::UrlSafe, false);
-            let token = format!("POP {}", base64::encode_config(raw_token, url_safe_config));
-
-            let _response = peer_handler
-                .get_keyserver_manager()
-                .uniform_broadcast_raw_metadata(
-                    &addr_str,
-                    db_wrapper.serialized_auth_wrapper,
-                    token,
-                    SETTINGS.peering.push_fan_size,
+        let database = self.database.clone();
+        let due: Vec<(Vec<u8>, BroadcastRecord)> =
+            task::spawn_blocking(move || database.get_broadcasts())
+                .await
+                .unwrap()
+                .into_iter()
+                .filter(|(_, broadcast_record)| broadcast_record.target_height <= height)
+                .collect();
+        if due.is_empty() {
+            return;
+        }
+
+        // Failing peers are penalized by the client and excluded once banned
+        let uris = peer_handler.get_urls().await;
+        let required = SETTINGS.peering.push_fan_size.min(uris.len());
+        for (addr_raw, broadcast_record) in due {
+            if let Err(err) = self
+                .broadcast(
+                    peer_handler,
+                    &uris,
+                    required,
+                    addr_raw,
+                    broadcast_record,
+                    height,
                 )
-                .await;
+                .await
+            {
+                error!(message = "failed to update broadcast queue", error = %err);
+            }
+        }
+    }
 
-            // TODO: Remove errors from peer list
+    /// Push metadata to peers which haven't accepted it, updating its queue entry.
+    async fn broadcast<S>(
+        &self,
+        peer_handler: &PeerHandler<S>,
+        uris: &[Uri],
+        required: usize,
+        addr_raw: Vec<u8>,
+        broadcast_record: BroadcastRecord,
+        height: u32,
+    ) -> Result<(), RocksError>
+    where
+        S: Service<Request<Body>, Response = Response<Body>>,
+        S: Send + Clone + 'static,
+        <S as Service<Request<Body>>>::Future: Send,
+        S::Error: Send + fmt::Debug + fmt::Display,
+    {
+        // Drop metadata which has since been invalidated
+        let db_wrapper = match self.database.get_metadata(&addr_raw)? {
+            Some(some) if !some.invalid && !some.operator_published => some,
+            _ => return self.database.delete_broadcast(&addr_raw),
+        };
+        let addr = Address {
+            body: addr_raw.clone(),
+            ..Default::default()
+        };
+        let addr_str = addr.encode().unwrap(); // This is safe
+
+        // Reconstruct token
+        let url_safe_config = base64::Config::new(base64::CharacterSet::UrlSafe, false);
+        let token = format!(
+            "POP {}",
+            base64::encode_config(&db_wrapper.token, url_safe_config)
+        );
+
+        // Push to peers which haven't accepted the metadata
+        let mut peers = broadcast_record.peers.clone();
+        let candidates: Vec<Uri> = uris
+            .iter()
+            .filter(|uri| !peers.contains(&uri.to_string()))
+            .cloned()
+            .collect();
+        let targets = peer_handler.sample_peers(&candidates, required.saturating_sub(peers.len()));
+        let results = future::join_all(targets.iter().map(|uri| {
+            peer_handler.push_metadata(
+                uri,
+                &addr_str,
+                db_wrapper.serialized_auth_wrapper.clone(),
+                &token,
+            )
+        }))
+        .await;
+        for (uri, result) in targets.into_iter().zip(results) {
+            match result {
+                Ok(()) => peers.push(uri.to_string()),
+                Err(err) => warn!(message = "failed to push metadata", peer = %uri, error = %err),
+            }
+        }
+
+        // Skip updating the entry if the metadata was replaced during the broadcast
+        let _guard = self.lock.lock().await;
+        if self.database.get_broadcast(&addr_raw)?.as_ref() != Some(&broadcast_record) {
+            return Ok(());
         }
+        let attempts = broadcast_record.attempts + 1;
+        if peers.len() >= required {
+            return self.database.delete_broadcast(&addr_raw);
+        }
+        if attempts >= MAX_ATTEMPTS {
+            warn!(message = "dropping metadata from broadcast queue", address = %addr_str, peers = peers.len());
+            return self.database.delete_broadcast(&addr_raw);
+        }
+        let backoff = (1 << attempts.min(8)).min(MAX_BACKOFF);
+        let broadcast_record = BroadcastRecord {
+            target_height: height + backoff,
+            peers,
+            attempts,
+        };
+        self.database.put_broadcast(&addr_raw, &broadcast_record)
     }
 }
@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use clap::App;
 use config::{Config, ConfigError, File};
@@ -6,6 +6,7 @@ use serde::Deserialize;
 
 const FOLDER_DIR: &str = ".keyserver";
 const DEFAULT_BIND: &str = "127.0.0.1:8080";
+const DEFAULT_PUBLIC_URL: &str = "http://127.0.0.1:8080";
 const DEFAULT_RPC_ADDR: &str = "http://127.0.0.1:18443";
 const DEFAULT_RPC_USER: &str = "user";
 const DEFAULT_RPC_PASSWORD: &str = "password";
@@ -15,24 +16,63 @@ const DEFAULT_METADATA_LIMIT: usize = 1_000 * 5; // 5KB
 const DEFAULT_PAYMENT_LIMIT: usize = 1_000 * 3; // 3KB
 const DEFAULT_TRUNCATION_LENGTH: usize = 500;
 const DEFAULT_MEMO: &str = "Thanks for your custom!";
+const DEFAULT_BASE_FEE: u64 = 0;
+const DEFAULT_PER_BYTE_FEE: u64 = 0;
+const DEFAULT_PRICING_TIERS: &[String] = &[];
+const DEFAULT_MIN_CONFIRMATIONS: u32 = 0;
+const DEFAULT_QUEUE_UNCONFIRMED: bool = false;
+const DEFAULT_MAX_QUEUED: u64 = 10_000;
+const DEFAULT_TOKEN_SCHEME: &str = "chain_commitment";
+const DEFAULT_VALIDATION_CACHE_SIZE: usize = 10_000;
+const DEFAULT_API_KEYS: &[String] = &[];
+const DEFAULT_TRUSTED_PROXIES: &[String] = &[];
 const DEFAULT_MAX_PEERS: u32 = 128;
 const DEFAULT_PEERING: bool = true;
 const DEFAULT_ZMQ_ADDRESS: &str = "tcp://127.0.0.1:28332";
+const DEFAULT_BLOCK_SOURCE: &str = "zmq";
+const DEFAULT_BLOCK_POLL_INTERVAL: u64 = 5_000;
 const DEFAULT_PEERS: &[String] = &[];
 const DEFAULT_PEER_TIMEOUT: u64 = 60_000;
 const DEFAULT_PEER_KEEP_ALIVE: u64 = 30_000;
 const DEFAULT_PEER_BROADCAST_DELAY: usize = 2;
 const DEFAULT_PEER_FAN_SIZE: usize = 4;
+const DEFAULT_PEER_CRAWL_INTERVAL: u64 = 60 * 60 * 1_000;
+const DEFAULT_PEER_ANNOUNCE: bool = true;
+const DEFAULT_PEER_REQUIRE_INFO: bool = false;
+const DEFAULT_PEER_SYNC_INTERVAL: u64 = 10 * 60 * 1_000;
+const DEFAULT_PEER_SAMPLING: &str = "uniform";
+const DEFAULT_PEER_PROXY_ONION_ONLY: bool = false;
+const DEFAULT_IDENTITY_PASSWORD: &str = "";
+const DEFAULT_PINNED_PEERS: &[String] = &[];
+const DEFAULT_REQUIRE_CLIENT_CERT: bool = false;
+const DEFAULT_CLIENT_CERT_HEADER: &str = "X-Client-Cert-Fingerprint";
+const DEFAULT_CLIENT_FINGERPRINTS: &[String] = &[];
 
 #[cfg(feature = "monitoring")]
 const DEFAULT_BIND_PROM: &str = "127.0.0.1:9095";
 
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockSourceKind {
+    /// Blocks are announced over ZMQ at `zmq_address`.
+    Zmq,
+    /// The best block hash is polled over RPC every `poll_interval`.
+    Poll,
+    /// Blocks are posted to the block notification endpoint, using bitcoind's `-blocknotify`.
+    Notify,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct BitcoinRpc {
     pub address: String,
     pub username: String,
     pub password: String,
     pub zmq_address: String,
+    pub block_source: BlockSourceKind,
+    /// Interval between best block polls, in milliseconds.
+    pub poll_interval: u64,
+    /// Secret required in the `Notify-Secret` header of block notifications.
+    pub notify_secret: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -41,9 +81,141 @@ pub struct Limits {
     pub payment_size: u64,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct PricingTier {
+    /// Smallest wrapper size, in bytes, the tier applies to.
+    pub min_size: u64,
+    pub base_fee: u64,
+    pub per_byte_fee: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Pricing {
+    pub base_fee: u64,
+    pub per_byte_fee: u64,
+    pub tiers: Vec<PricingTier>,
+}
+
+impl Pricing {
+    /// Price, in satoshis, of storing a wrapper of the given size.
+    ///
+    /// The largest tier whose `min_size` the size reaches takes precedence over the base pricing.
+    pub fn price(&self, size: u64) -> u64 {
+        let (base_fee, per_byte_fee) = self
+            .tiers
+            .iter()
+            .filter(|tier| tier.min_size <= size)
+            .max_by_key(|tier| tier.min_size)
+            .map(|tier| (tier.base_fee, tier.per_byte_fee))
+            .unwrap_or((self.base_fee, self.per_byte_fee));
+        base_fee.saturating_add(per_byte_fee.saturating_mul(size))
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Payment {
     pub memo: String,
+    pub address: Option<String>,
+    pub pricing: Pricing,
+    pub min_confirmations: u32,
+    pub queue_unconfirmed: bool,
+    /// Maximum number of metadata updates queued until their tokens confirm.
+    pub max_queued: u64,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenSchemeKind {
+    /// Tokens are outpoints of OP_RETURN commitments, paid for via the payments endpoint.
+    ChainCommitment,
+    /// Tokens are HMACs issued by the server using `hmac_secret`.
+    Hmac,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Tokens {
+    pub scheme: TokenSchemeKind,
+    /// Hex encoded secret key of the HMAC scheme.
+    pub hmac_secret: Option<String>,
+    /// Number of chain commitments cached between blocks.
+    pub cache_size: usize,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    /// Publish metadata without a POP token.
+    Metadata,
+    /// Manage peers through the admin endpoints.
+    Admin,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiKey {
+    /// Name identifying the key in logs.
+    pub name: String,
+    pub key: String,
+    pub scopes: Vec<ApiKeyScope>,
+    /// Maximum number of uses per day, unlimited if absent.
+    pub daily_quota: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RateLimit {
+    /// Maximum number of requests in a burst.
+    pub burst: u32,
+    /// Requests replenished per second.
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    /// Whether the limit admits requests at all, refilling at a positive rate.
+    pub fn is_valid(&self) -> bool {
+        self.burst > 0 && self.per_second.is_finite() && self.per_second > 0.
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RateLimits {
+    /// Proxies whose `X-Forwarded-For` headers are trusted.
+    pub trusted_proxies: Vec<IpAddr>,
+    pub keys_get: Option<RateLimit>,
+    pub keys_put: Option<RateLimit>,
+    pub payments: Option<RateLimit>,
+    pub peers: Option<RateLimit>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplingStrategyKind {
+    /// Peers are sampled uniformly at random.
+    Uniform,
+    /// Peers are sampled in proportion to their observed success rate and latency.
+    Weighted,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PinnedPeer {
+    /// https URL of the peer, matched by host, whose port is the only one the peer is reached on.
+    pub url: String,
+    /// Path to the PEM encoded leaf certificate the peer presents.
+    pub certificate: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PeerTls {
+    /// Path to the PKCS#12 archive holding the client certificate and private key presented to peers.
+    pub identity: Option<String>,
+    /// Password of the PKCS#12 archive.
+    pub identity_password: String,
+    /// Peers which must present their pinned certificate.
+    pub pinned_peers: Vec<PinnedPeer>,
+    /// Whether peer-only routes require a client certificate, verified by a trusted proxy.
+    pub require_client_cert: bool,
+    /// Header in which trusted proxies forward the SHA-256 fingerprint of the client certificate.
+    pub client_cert_header: String,
+    /// Fingerprints of client certificates accepted, in addition to those of pinned peers.
+    pub client_fingerprints: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -55,6 +227,21 @@ pub struct Peering {
     pub pull_fan_size: usize,
     pub push_fan_size: usize,
     pub broadcast_delay: usize,
+    /// Interval between peer crawls, in milliseconds.
+    pub crawl_interval: u64,
+    /// Whether crawls drop peers whose compatibility is unknown, rather than keeping them.
+    pub require_info: bool,
+    /// Whether to announce `public_url` to the seed peers on startup.
+    pub announce: bool,
+    /// Interval between metadata synchronizations with a peer, in milliseconds, zero disables it.
+    pub sync_interval: u64,
+    /// Strategy used to choose the peers sampled and pushed to.
+    pub sampling: SamplingStrategyKind,
+    /// SOCKS5 proxy peer connections are made through, connecting directly when absent.
+    pub proxy: Option<SocketAddr>,
+    /// Whether only `.onion` peers are reached through the proxy.
+    pub proxy_onion_only: bool,
+    pub tls: PeerTls,
     pub peers: Vec<String>,
 }
 
@@ -63,11 +250,15 @@ pub struct Settings {
     pub bind: SocketAddr,
     #[cfg(feature = "monitoring")]
     pub bind_prom: SocketAddr,
+    pub public_url: String,
     pub db_path: String,
     pub network: String,
     pub bitcoin_rpc: BitcoinRpc,
     pub limits: Limits,
     pub payments: Payment,
+    pub tokens: Tokens,
+    pub api_keys: Vec<ApiKey>,
+    pub rate_limits: RateLimits,
     pub peering: Peering,
 }
 
@@ -88,6 +279,7 @@ impl Settings {
             None => return Err(ConfigError::Message("no home directory".to_string())),
         };
         s.set_default("bind", DEFAULT_BIND)?;
+        s.set_default("public_url", DEFAULT_PUBLIC_URL)?;
         #[cfg(feature = "monitoring")]
         s.set_default("bind_prom", DEFAULT_BIND_PROM)?;
         s.set_default("network", DEFAULT_NETWORK)?;
@@ -99,17 +291,58 @@ impl Settings {
         s.set_default("bitcoin_rpc.username", DEFAULT_RPC_USER)?;
         s.set_default("bitcoin_rpc.password", DEFAULT_RPC_PASSWORD)?;
         s.set_default("bitcoin_rpc.zmq_address", DEFAULT_ZMQ_ADDRESS)?;
+        s.set_default("bitcoin_rpc.block_source", DEFAULT_BLOCK_SOURCE)?;
+        s.set_default(
+            "bitcoin_rpc.poll_interval",
+            DEFAULT_BLOCK_POLL_INTERVAL as i64,
+        )?;
 
         s.set_default("limits.metadata_size", DEFAULT_METADATA_LIMIT as i64)?;
         s.set_default("limits.payment_size", DEFAULT_PAYMENT_LIMIT as i64)?;
 
         s.set_default("payments.memo", DEFAULT_MEMO)?;
+        s.set_default("payments.pricing.base_fee", DEFAULT_BASE_FEE as i64)?;
+        s.set_default("payments.pricing.per_byte_fee", DEFAULT_PER_BYTE_FEE as i64)?;
+        s.set_default("payments.pricing.tiers", DEFAULT_PRICING_TIERS.to_vec())?;
+        s.set_default(
+            "payments.min_confirmations",
+            DEFAULT_MIN_CONFIRMATIONS as i64,
+        )?;
+        s.set_default("payments.queue_unconfirmed", DEFAULT_QUEUE_UNCONFIRMED)?;
+        s.set_default("payments.max_queued", DEFAULT_MAX_QUEUED as i64)?;
+
+        s.set_default("tokens.scheme", DEFAULT_TOKEN_SCHEME)?;
+        s.set_default("tokens.cache_size", DEFAULT_VALIDATION_CACHE_SIZE as i64)?;
+
+        s.set_default("api_keys", DEFAULT_API_KEYS.to_vec())?;
+
+        s.set_default(
+            "rate_limits.trusted_proxies",
+            DEFAULT_TRUSTED_PROXIES.to_vec(),
+        )?;
 
         s.set_default("peering.enabled", DEFAULT_PEERING)?;
         s.set_default("peering.max_peers", DEFAULT_MAX_PEERS as i64)?;
         s.set_default("peering.timeout", DEFAULT_PEER_TIMEOUT as i64)?;
         s.set_default("peering.keep_alive", DEFAULT_PEER_KEEP_ALIVE as i64)?;
         s.set_default("peering.peers", DEFAULT_PEERS.to_vec())?;
+        s.set_default("peering.announce", DEFAULT_PEER_ANNOUNCE)?;
+        s.set_default("peering.require_info", DEFAULT_PEER_REQUIRE_INFO)?;
+        s.set_default("peering.crawl_interval", DEFAULT_PEER_CRAWL_INTERVAL as i64)?;
+        s.set_default("peering.sync_interval", DEFAULT_PEER_SYNC_INTERVAL as i64)?;
+        s.set_default("peering.sampling", DEFAULT_PEER_SAMPLING)?;
+        s.set_default("peering.proxy_onion_only", DEFAULT_PEER_PROXY_ONION_ONLY)?;
+        s.set_default("peering.tls.identity_password", DEFAULT_IDENTITY_PASSWORD)?;
+        s.set_default("peering.tls.pinned_peers", DEFAULT_PINNED_PEERS.to_vec())?;
+        s.set_default(
+            "peering.tls.require_client_cert",
+            DEFAULT_REQUIRE_CLIENT_CERT,
+        )?;
+        s.set_default("peering.tls.client_cert_header", DEFAULT_CLIENT_CERT_HEADER)?;
+        s.set_default(
+            "peering.tls.client_fingerprints",
+            DEFAULT_CLIENT_FINGERPRINTS.to_vec(),
+        )?;
         s.set_default("peering.push_fan_size", DEFAULT_PEER_FAN_SIZE as i64)?;
         s.set_default("peering.pull_fan_size", DEFAULT_PEER_FAN_SIZE as i64)?;
         s.set_default(
@@ -140,6 +373,11 @@ impl Settings {
             s.set("bind_prom", bind_prom)?;
         }
 
+        // Set public URL from cmd line
+        if let Some(public_url) = matches.value_of("public-url") {
+            s.set("public_url", public_url)?;
+        }
+
         // Set the bitcoin network
         if let Some(network) = matches.value_of("network") {
             s.set("network", network)?;
@@ -170,6 +408,66 @@ impl Settings {
             s.set("bitcoin_rpc.zmq_address", rpc_password)?;
         }
 
-        s.try_into()
+        let settings: Self = s.try_into()?;
+
+        // Reject limits which would lock clients out
+        let rate_limits = &settings.rate_limits;
+        let limits = [
+            ("keys_get", &rate_limits.keys_get),
+            ("keys_put", &rate_limits.keys_put),
+            ("payments", &rate_limits.payments),
+            ("peers", &rate_limits.peers),
+        ];
+        for (name, limit) in limits.iter() {
+            if matches!(limit, Some(limit) if !limit.is_valid()) {
+                return Err(ConfigError::Message(format!(
+                    "rate_limits.{} requires a positive burst and per_second",
+                    name
+                )));
+            }
+        }
+
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limits() {
+        let limit = |burst, per_second| RateLimit { burst, per_second };
+        assert!(limit(5, 0.1).is_valid());
+        assert!(!limit(0, 0.1).is_valid());
+        assert!(!limit(5, 0.).is_valid());
+        assert!(!limit(5, -1.).is_valid());
+        assert!(!limit(5, f64::NAN).is_valid());
+    }
+
+    #[test]
+    fn pricing() {
+        let pricing = Pricing {
+            base_fee: 1_000,
+            per_byte_fee: 2,
+            tiers: vec![
+                PricingTier {
+                    min_size: 1_000,
+                    base_fee: 500,
+                    per_byte_fee: 3,
+                },
+                PricingTier {
+                    min_size: 3_000,
+                    base_fee: 0,
+                    per_byte_fee: 5,
+                },
+            ],
+        };
+
+        assert_eq!(pricing.price(0), 1_000);
+        assert_eq!(pricing.price(200), 1_400);
+        assert_eq!(pricing.price(1_000), 3_500);
+        assert_eq!(pricing.price(2_999), 9_497);
+        assert_eq!(pricing.price(5_000), 25_000);
     }
 }
@@ -0,0 +1,150 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+/// Commitment found at an outpoint, alongside the fee paid by its transaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedCommitment {
+    pub commitment: Vec<u8>,
+    /// Satoshis paid to the payment address.
+    pub fee: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    commitments: HashMap<Vec<u8>, CachedCommitment>,
+    order: VecDeque<Vec<u8>>,
+}
+
+/// Bounded cache of the commitments found at outpoints, evicting the oldest entries first.
+///
+/// The cache must be cleared on each block, as any entry may have been reorged out, and the
+/// commitments of double-spent transactions evicted.
+#[derive(Clone, Debug, Default)]
+pub struct ValidationCache {
+    state: Arc<Mutex<CacheState>>,
+    capacity: usize,
+}
+
+impl ValidationCache {
+    /// Construct new [`ValidationCache`], caching nothing if the capacity is zero.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Default::default(),
+            capacity,
+        }
+    }
+
+    /// Get the commitment found at an outpoint.
+    pub fn get(&self, outpoint_raw: &[u8]) -> Option<CachedCommitment> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let commitment = self
+            .state
+            .lock()
+            .unwrap()
+            .commitments
+            .get(outpoint_raw)
+            .cloned();
+
+        #[cfg(feature = "monitoring")]
+        {
+            let result = if commitment.is_some() { "hit" } else { "miss" };
+            crate::monitoring::VALIDATION_CACHE_TOTAL
+                .with_label_values(&[result])
+                .inc();
+        }
+
+        commitment
+    }
+
+    /// Insert the commitment found at an outpoint.
+    pub fn insert(&self, outpoint_raw: Vec<u8>, commitment: CachedCommitment) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state
+            .commitments
+            .insert(outpoint_raw.clone(), commitment)
+            .is_none()
+        {
+            state.order.push_back(outpoint_raw);
+        }
+        while state.order.len() > self.capacity {
+            if let Some(oldest) = state.order.pop_front() {
+                state.commitments.remove(&oldest);
+            }
+        }
+    }
+
+    /// Evict every commitment.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.commitments.clear();
+        state.order.clear();
+    }
+
+    /// Evict the commitments of a transaction.
+    pub fn evict_transaction(&self, tx_id: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .commitments
+            .retain(|outpoint_raw, _| !outpoint_raw.starts_with(tx_id));
+        state
+            .order
+            .retain(|outpoint_raw| !outpoint_raw.starts_with(tx_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(commitment: u8) -> CachedCommitment {
+        CachedCommitment {
+            commitment: vec![commitment],
+            fee: 0,
+        }
+    }
+
+    #[test]
+    fn bounded_eviction() {
+        let cache = ValidationCache::new(2);
+        cache.insert([[1; 32].to_vec(), vec![0; 4]].concat(), cached(1));
+        cache.insert([[2; 32].to_vec(), vec![0; 4]].concat(), cached(2));
+        cache.insert([[2; 32].to_vec(), vec![1, 0, 0, 0]].concat(), cached(3));
+
+        // Oldest entry is evicted
+        assert!(cache
+            .get(&[[1; 32].to_vec(), vec![0; 4]].concat())
+            .is_none());
+        assert_eq!(
+            cache.get(&[[2; 32].to_vec(), vec![0; 4]].concat()),
+            Some(cached(2))
+        );
+
+        // Transaction is evicted
+        cache.evict_transaction(&[2; 32]);
+        assert!(cache
+            .get(&[[2; 32].to_vec(), vec![0; 4]].concat())
+            .is_none());
+        assert!(cache
+            .get(&[[2; 32].to_vec(), vec![1, 0, 0, 0]].concat())
+            .is_none());
+
+        // Everything is evicted
+        cache.insert([[3; 32].to_vec(), vec![0; 4]].concat(), cached(3));
+        cache.clear();
+        assert!(cache
+            .get(&[[3; 32].to_vec(), vec![0; 4]].concat())
+            .is_none());
+
+        // Nothing is cached without capacity
+        let cache = ValidationCache::new(0);
+        cache.insert(vec![1; 36], cached(1));
+        assert!(cache.get(&[1; 36]).is_none());
+    }
+}
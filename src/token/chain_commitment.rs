@@ -0,0 +1,179 @@
+use std::convert::TryInto;
+
+use cashweb::{
+    bitcoin::{transaction::Transaction, Decodable},
+    token::schemes::chain_commitment::construct_commitment,
+};
+use futures::{future::BoxFuture, prelude::*};
+
+use super::{CachedCommitment, TokenInspection, TokenScheme, ValidationCache, ValidationError};
+use crate::{net::FEE_SCRIPT, node::Node, SETTINGS};
+
+const COMMITMENT_LEN: usize = 32;
+
+/// Chain commitment scheme used in the keyserver protocol, validating tokens against a [`Node`].
+#[derive(Clone, Debug)]
+pub struct ChainCommitmentScheme<N> {
+    node: N,
+    cache: ValidationCache,
+}
+
+impl<N: Node> ChainCommitmentScheme<N> {
+    /// Construct new [`ChainCommitmentScheme`].
+    pub fn new(node: N) -> Self {
+        Self {
+            node,
+            cache: ValidationCache::default(),
+        }
+    }
+
+    /// Cache the commitments found at outpoints, sparing node lookups.
+    pub fn with_cache(mut self, cache: ValidationCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    async fn validate(
+        &self,
+        pub_key_hash: &[u8],
+        address_metadata_hash: &[u8],
+        token: &str,
+        wrapper_size: usize,
+        inspection: &mut TokenInspection,
+    ) -> Result<Vec<u8>, ValidationError> {
+        let url_safe_config = base64::Config::new(base64::CharacterSet::UrlSafe, false);
+        let outpoint_raw =
+            base64::decode_config(token, url_safe_config).map_err(ValidationError::Base64)?;
+
+        // Check token length
+        if outpoint_raw.len() != 32 + 4 {
+            return Err(ValidationError::TokenLength);
+        }
+        let tx_id = &outpoint_raw[..32];
+        let vout_raw: [u8; 4] = outpoint_raw[32..36].try_into().unwrap(); // This is safe
+        let vout = u32::from_le_bytes(vout_raw);
+        inspection.outpoint = Some((tx_id.to_vec(), vout));
+
+        // Check cached commitment
+        let expected_commitment = construct_commitment(pub_key_hash, address_metadata_hash);
+        let required = SETTINGS.payments.pricing.price(wrapper_size as u64);
+        if let Some(cached) = self.cache.get(&outpoint_raw) {
+            if expected_commitment[..] != cached.commitment[..] {
+                return Err(ValidationError::Invalid);
+            }
+            if cached.fee < required {
+                return Err(ValidationError::Underpaid(cached.fee, required));
+            }
+            return Ok(outpoint_raw);
+        }
+
+        // Get transaction
+        let (raw_transaction, confirmations) = self
+            .node
+            .get_transaction(tx_id)
+            .await
+            .map_err(ValidationError::Node)?;
+        inspection.transaction_found = true;
+        inspection.confirmations = Some(confirmations);
+        let transaction = Transaction::decode(&mut raw_transaction.as_slice())
+            .map_err(ValidationError::Transaction)?;
+
+        // Get output
+        let output = transaction
+            .outputs
+            .get(vout as usize)
+            .ok_or(ValidationError::OutputNotFound)?;
+
+        if !output.script.is_op_return() {
+            return Err(ValidationError::NotOpReturn);
+        }
+
+        // Check commitment
+        let raw_script = output.script.as_bytes();
+        if raw_script.len() != 2 + COMMITMENT_LEN || raw_script[1] != COMMITMENT_LEN as u8 {
+            return Err(ValidationError::IncorrectLength);
+        }
+        let commitment_matches = expected_commitment[..] == raw_script[2..];
+        inspection.commitment_matches = Some(commitment_matches);
+
+        // Sum the fee paid to the payment address by the commitment transaction
+        let fee = FEE_SCRIPT
+            .as_ref()
+            .map(|fee_script| {
+                transaction
+                    .outputs
+                    .iter()
+                    .filter(|output| output.script.as_bytes() == &fee_script[..])
+                    .fold(0u64, |total, output| total.saturating_add(output.value))
+            })
+            .unwrap_or_default();
+
+        // Cached until the next block, double-spent commitments being evicted by the watcher
+        self.cache.insert(
+            outpoint_raw.clone(),
+            CachedCommitment {
+                commitment: raw_script[2..].to_vec(),
+                fee,
+            },
+        );
+
+        if !commitment_matches {
+            return Err(ValidationError::Invalid);
+        }
+        if fee < required {
+            return Err(ValidationError::Underpaid(fee, required));
+        }
+
+        Ok(outpoint_raw)
+    }
+}
+
+impl<N: Node> TokenScheme for ChainCommitmentScheme<N> {
+    fn validate_token<'a>(
+        &'a self,
+        pub_key_hash: &'a [u8],
+        address_metadata_hash: &'a [u8],
+        token: &'a str,
+        wrapper_size: usize,
+    ) -> BoxFuture<'a, Result<Vec<u8>, ValidationError>> {
+        async move {
+            let mut inspection = TokenInspection::default();
+            self.validate(
+                pub_key_hash,
+                address_metadata_hash,
+                token,
+                wrapper_size,
+                &mut inspection,
+            )
+            .await
+        }
+        .boxed()
+    }
+
+    fn inspect_token<'a>(
+        &'a self,
+        pub_key_hash: &'a [u8],
+        address_metadata_hash: &'a [u8],
+        token: &'a str,
+        wrapper_size: usize,
+    ) -> BoxFuture<'a, (TokenInspection, Result<Vec<u8>, ValidationError>)> {
+        async move {
+            let mut inspection = TokenInspection::default();
+            let result = self
+                .validate(
+                    pub_key_hash,
+                    address_metadata_hash,
+                    token,
+                    wrapper_size,
+                    &mut inspection,
+                )
+                .await;
+            (inspection, result)
+        }
+        .boxed()
+    }
+
+    fn commitment_tx_id<'a>(&self, raw_token: &'a [u8]) -> Option<&'a [u8]> {
+        raw_token.get(..32)
+    }
+}
@@ -0,0 +1,77 @@
+use cashweb::token::schemes::hmac_bearer::{HmacScheme, ValidationError as HmacValidationError};
+use futures::future::{self, BoxFuture, FutureExt};
+
+use super::{TokenScheme, ValidationError};
+
+/// Scheme for tokens issued by the server, or an operator, using a secret key.
+///
+/// Tokens are HMACs of the public key hash concatenated with the metadata digest.
+#[derive(Debug)]
+pub struct HmacTokenScheme {
+    scheme: HmacScheme,
+}
+
+impl HmacTokenScheme {
+    /// Construct new [`HmacTokenScheme`] from a secret key.
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            scheme: HmacScheme::new(secret),
+        }
+    }
+
+    /// Issue a token bound to a public key hash and metadata digest.
+    pub fn construct_token(&self, pub_key_hash: &[u8], address_metadata_hash: &[u8]) -> String {
+        self.scheme
+            .construct_token(&[pub_key_hash, address_metadata_hash].concat())
+    }
+}
+
+impl TokenScheme for HmacTokenScheme {
+    fn validate_token<'a>(
+        &'a self,
+        pub_key_hash: &'a [u8],
+        address_metadata_hash: &'a [u8],
+        token: &'a str,
+        _wrapper_size: usize,
+    ) -> BoxFuture<'a, Result<Vec<u8>, ValidationError>> {
+        let data = [pub_key_hash, address_metadata_hash].concat();
+        let result = self
+            .scheme
+            .validate_token(&data, token)
+            .map_err(|err| match err {
+                HmacValidationError::Base64(err) => ValidationError::Base64(err),
+                HmacValidationError::Invalid => ValidationError::Invalid,
+            })
+            .and_then(|_| {
+                let url_safe_config = base64::Config::new(base64::CharacterSet::UrlSafe, false);
+                base64::decode_config(token, url_safe_config).map_err(ValidationError::Base64)
+            });
+        future::ready(result).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn construct_validate() {
+        let scheme = HmacTokenScheme::new(b"secret");
+        let token = scheme.construct_token(&[1; 32], &[2; 32]);
+
+        assert!(scheme
+            .validate_token(&[1; 32], &[2; 32], &token, 0)
+            .await
+            .is_ok());
+        assert!(matches!(
+            scheme.validate_token(&[1; 32], &[3; 32], &token, 0).await,
+            Err(ValidationError::Invalid)
+        ));
+        assert!(matches!(
+            HmacTokenScheme::new(b"other")
+                .validate_token(&[1; 32], &[2; 32], &token, 0)
+                .await,
+            Err(ValidationError::Invalid)
+        ));
+    }
+}
@@ -0,0 +1,78 @@
+pub mod cache;
+pub mod chain_commitment;
+pub mod hmac;
+
+pub use cache::*;
+pub use chain_commitment::*;
+pub use hmac::*;
+
+use cashweb::bitcoin::transaction::DecodeError as TransactionDecodeError;
+use futures::future::{BoxFuture, FutureExt};
+use thiserror::Error;
+
+use crate::node::NodeError;
+
+#[derive(Debug, Error)]
+pub enum ValidationError {
+    #[error("failed to decode token: {0}")]
+    Base64(base64::DecodeError),
+    #[error("unexpected script length")]
+    IncorrectLength,
+    #[error("invalid token")]
+    Invalid,
+    #[error(transparent)]
+    Node(NodeError),
+    #[error("output is not an op return format")]
+    NotOpReturn,
+    #[error("output missing")]
+    OutputNotFound,
+    #[error("failed to decode transaction: {0}")]
+    Transaction(TransactionDecodeError),
+    #[error("unexpected token length")]
+    TokenLength,
+    #[error("commitment transaction paid {0} satoshis, {1} required")]
+    Underpaid(u64, u64),
+}
+
+/// Progress of a token through validation.
+#[derive(Debug, Default)]
+pub struct TokenInspection {
+    /// Transaction ID and output index decoded from the token.
+    pub outpoint: Option<(Vec<u8>, u32)>,
+    pub transaction_found: bool,
+    pub confirmations: Option<u32>,
+    /// Whether the output matches the expected commitment, if it is a commitment at all.
+    pub commitment_matches: Option<bool>,
+}
+
+/// Scheme validating the POP tokens authorizing metadata updates.
+pub trait TokenScheme: Send + Sync {
+    /// Validate a token bound to a public key hash and metadata digest, returning the raw token.
+    ///
+    /// Schemes charging for tokens check they paid the price of a wrapper of the given size.
+    fn validate_token<'a>(
+        &'a self,
+        pub_key_hash: &'a [u8],
+        address_metadata_hash: &'a [u8],
+        token: &'a str,
+        wrapper_size: usize,
+    ) -> BoxFuture<'a, Result<Vec<u8>, ValidationError>>;
+
+    /// Validate a token, recording how far it got in an inspection alongside the result.
+    fn inspect_token<'a>(
+        &'a self,
+        pub_key_hash: &'a [u8],
+        address_metadata_hash: &'a [u8],
+        token: &'a str,
+        wrapper_size: usize,
+    ) -> BoxFuture<'a, (TokenInspection, Result<Vec<u8>, ValidationError>)> {
+        self.validate_token(pub_key_hash, address_metadata_hash, token, wrapper_size)
+            .map(|result| (TokenInspection::default(), result))
+            .boxed()
+    }
+
+    /// Get the ID of the transaction a raw token commits to, if the scheme uses one.
+    fn commitment_tx_id<'a>(&self, _raw_token: &'a [u8]) -> Option<&'a [u8]> {
+        None
+    }
+}
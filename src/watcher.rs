@@ -0,0 +1,130 @@
+use cashweb::bitcoin::{transaction::Transaction, Decodable};
+use rocksdb::Error as RocksError;
+use tracing::{error, info, warn};
+
+use crate::{
+    db::Database,
+    models::database::WatchRecord,
+    node::{tx_id, Node},
+    token::ValidationCache,
+};
+
+/// Confirmations after which a commitment transaction is no longer watched.
+const WATCH_DEPTH: u32 = 10;
+
+/// Watches the commitment transactions of stored tokens, invalidating metadata whose commitment
+/// transaction has been reorged out or double-spent.
+#[derive(Clone)]
+pub struct TokenWatcher<N> {
+    node: N,
+    database: Database,
+    validation_cache: ValidationCache,
+}
+
+impl<N: Node> TokenWatcher<N> {
+    /// Construct new [`TokenWatcher`], evicting vanished commitments from the validation cache.
+    pub fn new(node: N, database: Database, validation_cache: ValidationCache) -> Self {
+        Self {
+            node,
+            database,
+            validation_cache,
+        }
+    }
+
+    /// Re-validate every watched commitment transaction, called on each new block.
+    pub async fn process_block(&self) {
+        // Cached commitments may have been reorged out
+        self.validation_cache.clear();
+        for (tx_id, watch_record) in self.database.get_watches() {
+            self.revalidate(&tx_id, watch_record).await;
+        }
+    }
+
+    /// Re-validate the watched commitment transactions conflicting with a new transaction.
+    pub async fn process_transaction(&self, raw_tx: &[u8]) {
+        let transaction = match Transaction::decode(&mut &raw_tx[..]) {
+            Ok(some) => some,
+            Err(err) => {
+                warn!(message = "failed to decode transaction", error = %err);
+                return;
+            }
+        };
+        let tx_id = tx_id(raw_tx);
+
+        for outpoint in outpoints(&transaction) {
+            let spender_tx_id = match self.database.get_outpoint_spender(&outpoint) {
+                Ok(Some(some)) if some != tx_id => some,
+                Ok(_) => continue,
+                Err(err) => {
+                    error!(message = "failed to read outpoint index", error = %err);
+                    continue;
+                }
+            };
+            info!(message = "commitment transaction conflicted", tx_id = %hex::encode(&spender_tx_id));
+            match self.database.get_watch(&spender_tx_id) {
+                Ok(Some(watch_record)) => self.revalidate(&spender_tx_id, watch_record).await,
+                Ok(None) => (),
+                Err(err) => error!(message = "failed to read watch record", error = %err),
+            }
+        }
+    }
+
+    async fn revalidate(&self, tx_id: &[u8], watch_record: WatchRecord) {
+        let result = match self.node.get_confirmations(tx_id).await {
+            Ok(confirmations) if confirmations >= WATCH_DEPTH => self.database.delete_watch(tx_id),
+            Ok(_) if watch_record.outpoints.is_empty() => self.index(tx_id, watch_record).await,
+            Ok(_) => Ok(()),
+            Err(err) if err.is_not_found() => self.invalidate(tx_id, &watch_record),
+            Err(err) => {
+                warn!(message = "failed to get confirmations", error = %err);
+                Ok(())
+            }
+        };
+        if let Err(err) = result {
+            error!(message = "failed to update watch record", error = %err);
+        }
+    }
+
+    /// Index the outpoints spent by a commitment transaction, so conflicts can be detected.
+    async fn index(&self, tx_id: &[u8], mut watch_record: WatchRecord) -> Result<(), RocksError> {
+        let raw_tx = match self.node.get_raw_transaction(tx_id).await {
+            Ok(some) => some,
+            Err(err) => {
+                warn!(message = "failed to get commitment transaction", error = %err);
+                return Ok(());
+            }
+        };
+        if let Ok(transaction) = Transaction::decode(&mut raw_tx.as_slice()) {
+            watch_record.outpoints = outpoints(&transaction);
+            self.database.put_watch(tx_id, &watch_record)?;
+        }
+        Ok(())
+    }
+
+    /// Invalidate the metadata committed to by a vanished transaction.
+    fn invalidate(&self, tx_id: &[u8], watch_record: &WatchRecord) -> Result<(), RocksError> {
+        self.validation_cache.evict_transaction(tx_id);
+        if self
+            .database
+            .invalidate_metadata(&watch_record.address, tx_id)?
+        {
+            info!(message = "invalidated metadata", tx_id = %hex::encode(tx_id));
+        }
+        self.database.delete_watch(tx_id)
+    }
+}
+
+/// Serialize the outpoints spent by a transaction.
+fn outpoints(transaction: &Transaction) -> Vec<Vec<u8>> {
+    transaction
+        .inputs
+        .iter()
+        .map(|input| {
+            [
+                &input.outpoint.tx_id[..],
+                &input.outpoint.vout.to_le_bytes(),
+            ]
+            .concat()
+        })
+        .collect()
+}
@@ -30,6 +30,12 @@ class TestPop(TestCase):
         self.assertEqual(response.status_code, 402)
         payment_request = PaymentRequest.FromString(response.content)
 
+        # Get QR code of the BIP21 URI
+        response = keyserver_client.get_invoice_qr(
+            response.headers["Payment-Uri"])
+        self.assertEqual(response.status_code, 200)
+        self.assertEqual(response.headers["Content-Type"], "image/png")
+
     def test_put_get_using_pop(self):
         """Obtain a POP token, PUT with it then GET"""
 
@@ -74,3 +80,74 @@ class TestPop(TestCase):
             address)
         self.assertEqual(response.status_code, 200)
         self.assertEqual(response.content, raw_auth_wrapper)
+
+    def test_payment_recovery(self):
+        """Resend a payment and recover its token by transaction ID"""
+
+        # Construct auth wrapper
+        address, keypair = generate_random_keypair()
+        metadata = construct_dummy_metadata()
+        auth_wrapper, _ = construct_auth_wrapper(metadata, keypair)
+        raw_auth_wrapper = auth_wrapper.SerializeToString()
+
+        # Get PaymentRequest
+        response = keyserver_client.put_metadata_no_token(
+            address, raw_auth_wrapper)
+        self.assertEqual(response.status_code, 402)
+        payment_request = PaymentRequest.FromString(response.content)
+        payment_details = PaymentDetails.FromString(
+            payment_request.serialized_payment_details)
+
+        # Send payment twice
+        payment = bitcoin_client.generate_payment_from_payment_request(
+            payment_details)
+        payment_raw = payment.SerializeToString()
+        response_a = keyserver_client.send_payment(payment_raw)
+        self.assertEqual(response_a.status_code, 200)
+        response_b = keyserver_client.send_payment(payment_raw)
+        self.assertEqual(response_b.status_code, 200)
+        self.assertEqual(response_a.content, response_b.content)
+        self.assertEqual(
+            response_a.headers["Authorization"], response_b.headers["Authorization"])
+
+        # Recover token using the transaction ID
+        raw_tx = payment.transactions[0]
+        tx_id = sha256(sha256(raw_tx).digest()).digest()[::-1].hex()
+        response = keyserver_client.get_payment(tx_id)
+        self.assertEqual(response.status_code, 200)
+        self.assertEqual(
+            response.headers["Authorization"], response_a.headers["Authorization"])
+
+    def test_put_get_using_json_payment(self):
+        """Obtain a POP token using the JSON Payment Protocol, PUT with it then GET"""
+
+        # Construct auth wrapper
+        address, keypair = generate_random_keypair()
+        metadata = construct_dummy_metadata()
+        auth_wrapper, _ = construct_auth_wrapper(metadata, keypair)
+        raw_auth_wrapper = auth_wrapper.SerializeToString()
+
+        # Get JSON payment request
+        response = keyserver_client.put_metadata_no_token_json(
+            address, raw_auth_wrapper)
+        self.assertEqual(response.status_code, 402)
+        self.assertEqual(
+            response.headers["Content-Type"], "application/payment-request")
+        payment_request = response.json()
+
+        # Send JSON payment
+        op_return = bytes.fromhex(payment_request["outputs"][0]["script"])[2:]
+        raw_tx = bitcoin_client.construct_op_return(op_return)
+        payment = {
+            "currency": payment_request["currency"],
+            "transactions": [raw_tx.hex()]
+        }
+        response = keyserver_client.send_payment_json(
+            payment_request["paymentUrl"], payment)
+        self.assertEqual(response.status_code, 200)
+        self.assertEqual(response.json()["payment"]["transactions"], [raw_tx.hex()])
+
+        token = response.headers["Authorization"]
+        response = keyserver_client.put_metadata(
+            address, raw_auth_wrapper, token)
+        self.assertEqual(response.status_code, 200)
@@ -2,6 +2,7 @@ from requests import get, put, post, Response
 
 KEYS_URL = "{}/keys/{}"
 PAYMENTS_URL = "{}/payments"
+PAYMENT_URL = "{}/payments/{}"
 PEERS_URL = "{}/peers"
 
 class KeyserverClient:
@@ -30,6 +31,20 @@ class KeyserverClient:
         response = put(url=KEYS_URL.format(self.url, address), data=raw_metadata)
         return response
 
+    def put_metadata_no_token_json(self, address: str, raw_metadata: bytes) -> Response:
+        response = put(url=KEYS_URL.format(self.url, address), data=raw_metadata, headers={
+            "Accept": "application/payment-request"
+        })
+        return response
+
+    def send_payment_json(self, payment_url: str, payment: dict) -> Response:
+        headers = {
+            "Content-Type": "application/payment",
+            "Accept": "application/payment-ack"
+        }
+        response = post(url=payment_url, json=payment, headers=headers)
+        return response
+
     def send_payment(self, raw_payment: bytes) -> Response:
         headers = {
             "Content-Type": "application/bitcoincash-payment",
@@ -38,6 +53,15 @@ class KeyserverClient:
         response = post(url=PAYMENTS_URL.format(self.url), data=raw_payment, headers=headers)
         return response
 
+    def get_payment(self, tx_id: str) -> Response:
+        response = get(url=PAYMENT_URL.format(self.url, tx_id))
+        return response
+
+    def get_invoice_qr(self, payment_uri: str) -> Response:
+        invoice_url = payment_uri[len("bitcoincash:?r="):]
+        response = get(url=invoice_url + "/qr.png")
+        return response
+
     def get_peers(self) -> Response:
         response = get(url=PEERS_URL.format(self.url))
         return response
\ No newline at end of file
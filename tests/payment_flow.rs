@@ -0,0 +1,388 @@
+use bitcoincash_addr::Address;
+use cashweb::{
+    bitcoin::{
+        transaction::{outpoint::Outpoint, Input, Output, Script, Transaction},
+        Encodable,
+    },
+    secp256k1::{key::SecretKey, Message as SecpMessage, PublicKey, Secp256k1},
+};
+use prost::Message as _;
+use ring::digest::{digest, SHA256};
+use rocksdb::{Options, DB};
+use serde_json::{json, Value};
+use url::Url;
+use warp::{http::header::AUTHORIZATION, test::request, Filter, Rejection, Reply};
+
+use keyserver::{
+    db::Database,
+    models::wrapper::{AuthWrapper, SignatureScheme},
+    net::PendingQueue,
+    node::{tx_id, BlockNotifier, MockNode},
+    peering::{DigestCache, PeerHandler, TokenCache},
+    rest_api,
+    token::ValidationCache,
+    watcher::TokenWatcher,
+    SETTINGS,
+};
+
+/// State backing a REST API constructed by [`test_api`].
+struct TestState {
+    path: String,
+    database: Database,
+    node: MockNode,
+    validation_cache: ValidationCache,
+}
+
+impl TestState {
+    /// Destroy the database, once the REST API has been dropped.
+    fn destroy(self) {
+        drop(self.database);
+        DB::destroy(&Options::default(), &self.path).unwrap();
+    }
+}
+
+/// Construct the REST API over a fresh database and a mock node.
+fn test_api(
+    name: &str,
+) -> (
+    impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone,
+    TestState,
+) {
+    let path = format!("./tests/{}", name);
+    let database = Database::try_new(&path).unwrap();
+    let node = MockNode::new();
+    let validation_cache = ValidationCache::new(16);
+    let api = rest_api(
+        database.clone(),
+        node.clone(),
+        PeerHandler::new(vec![]),
+        TokenCache::new(database.clone()),
+        DigestCache::new(database.clone()),
+        PendingQueue::new(database.clone()),
+        validation_cache.clone(),
+        BlockNotifier::default(),
+    );
+    let state = TestState {
+        path,
+        database,
+        node,
+        validation_cache,
+    };
+    (api, state)
+}
+
+/// Construct a signed authorization wrapper around a payload.
+fn signed_auth_wrapper(payload: Vec<u8>) -> Vec<u8> {
+    let secp = Secp256k1::new();
+    let secret_key = SecretKey::from_slice(&[0xcd; 32]).unwrap();
+    let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+
+    let payload_digest = digest(&SHA256, &payload);
+    let message = SecpMessage::from_slice(payload_digest.as_ref()).unwrap();
+    let signature = secp.sign(&message, &secret_key);
+
+    let auth_wrapper = AuthWrapper {
+        public_key: public_key.serialize().to_vec(),
+        signature: signature.serialize_compact().to_vec(),
+        scheme: SignatureScheme::Ecdsa as i32,
+        payload,
+        payload_digest: payload_digest.as_ref().to_vec(),
+    };
+    let mut raw_auth_wrapper = Vec::with_capacity(auth_wrapper.encoded_len());
+    auth_wrapper.encode(&mut raw_auth_wrapper).unwrap();
+    raw_auth_wrapper
+}
+
+/// Construct a transaction paying to the outputs of a JSON payment request.
+fn pay(payment_request: &Value) -> Vec<u8> {
+    let outputs = payment_request["outputs"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|output| Output {
+            value: output["amount"].as_u64().unwrap(),
+            script: Script(hex::decode(output["script"].as_str().unwrap()).unwrap()),
+        })
+        .collect();
+    let transaction = Transaction {
+        version: 1,
+        inputs: vec![Input {
+            outpoint: Outpoint {
+                tx_id: [1; 32],
+                vout: 0,
+            },
+            script: Script(vec![]),
+            sequence: 0xffff_ffff,
+        }],
+        outputs,
+        lock_time: 0,
+    };
+    let mut raw_tx = Vec::with_capacity(transaction.encoded_len());
+    transaction.encode(&mut raw_tx).unwrap();
+    raw_tx
+}
+
+/// Pay for and put metadata, returning the token and the raw payment transaction.
+async fn put_using_payment<F>(
+    api: &F,
+    metadata_path: &str,
+    raw_auth_wrapper: &[u8],
+) -> (String, Vec<u8>)
+where
+    F: Filter + 'static,
+    F::Extract: Reply + Send,
+{
+    // Put without a token
+    let response = request()
+        .method("PUT")
+        .path(metadata_path)
+        .header("Accept", "application/payment-request")
+        .body(raw_auth_wrapper)
+        .reply(api)
+        .await;
+    assert_eq!(response.status(), 402);
+    let payment_request: Value = serde_json::from_slice(response.body()).unwrap();
+
+    // Pay the invoice
+    let raw_tx = pay(&payment_request);
+    let payment_url = Url::parse(payment_request["paymentUrl"].as_str().unwrap()).unwrap();
+    let payment = json!({
+        "currency": "BCH",
+        "transactions": [hex::encode(&raw_tx)],
+    });
+    let response = request()
+        .method("POST")
+        .path(&format!(
+            "{}?{}",
+            payment_url.path(),
+            payment_url.query().unwrap()
+        ))
+        .header("Content-Type", "application/payment")
+        .header("Accept", "application/payment-ack")
+        .body(serde_json::to_vec(&payment).unwrap())
+        .reply(api)
+        .await;
+    assert_eq!(response.status(), 200);
+    let token = response.headers()[AUTHORIZATION]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    // Put with the token
+    let response = request()
+        .method("PUT")
+        .path(metadata_path)
+        .header("Authorization", token.as_str())
+        .body(raw_auth_wrapper)
+        .reply(api)
+        .await;
+    assert_eq!(response.status(), 200);
+
+    (token, raw_tx)
+}
+
+fn metadata_path() -> String {
+    let address = Address {
+        body: vec![1; 20],
+        ..Default::default()
+    };
+    format!("/keys/{}", address.encode().unwrap())
+}
+
+#[tokio::test]
+async fn put_get_using_payment() {
+    let (api, state) = test_api("payment_flow");
+
+    let metadata_path = metadata_path();
+    let raw_auth_wrapper = signed_auth_wrapper(b"metadata".to_vec());
+    let (token, raw_tx) = put_using_payment(&api, &metadata_path, &raw_auth_wrapper).await;
+    assert_eq!(state.node.broadcasts(), vec![raw_tx.clone()]);
+
+    // The unconfirmed commitment is cached until the next block
+    let url_safe_config = base64::Config::new(base64::CharacterSet::UrlSafe, false);
+    let outpoint_raw =
+        base64::decode_config(token.trim_start_matches("POP "), url_safe_config).unwrap();
+    assert!(state.validation_cache.get(&outpoint_raw).is_some());
+
+    // Recover the token
+    let response = request()
+        .method("GET")
+        .path(&format!("/payments/{}", hex::encode(tx_id(&raw_tx))))
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()[AUTHORIZATION], token.as_str());
+
+    // Get the metadata
+    let response = request()
+        .method("GET")
+        .path(&metadata_path)
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()[AUTHORIZATION], token.as_str());
+    assert_eq!(&response.body()[..], &raw_auth_wrapper[..]);
+
+    drop(api);
+    state.destroy();
+}
+
+#[tokio::test]
+async fn withhold_double_spent_metadata() {
+    let (api, state) = test_api("double_spend_flow");
+    let token_watcher = TokenWatcher::new(
+        state.node.clone(),
+        state.database.clone(),
+        state.validation_cache.clone(),
+    );
+
+    let metadata_path = metadata_path();
+    let raw_auth_wrapper = signed_auth_wrapper(b"metadata".to_vec());
+    let (_, raw_tx) = put_using_payment(&api, &metadata_path, &raw_auth_wrapper).await;
+
+    // Index the commitment transaction
+    state.node.mine_block();
+    token_watcher.process_block().await;
+
+    // Double-spend the commitment transaction
+    let mut conflicting_tx = raw_tx.clone();
+    let last = conflicting_tx.len() - 1;
+    conflicting_tx[last] ^= 1; // Change the lock time
+    state
+        .node
+        .remove_transaction(&tx_id(&raw_tx), Some(conflicting_tx.clone()));
+    token_watcher.process_transaction(&conflicting_tx).await;
+
+    // Get the metadata
+    let response = request()
+        .method("GET")
+        .path(&metadata_path)
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), 410);
+
+    drop(api);
+    drop(token_watcher);
+    state.destroy();
+}
+
+#[tokio::test]
+async fn introspect_token() {
+    let (api, state) = test_api("introspection_flow");
+
+    let metadata_path = metadata_path();
+    let raw_auth_wrapper = signed_auth_wrapper(b"metadata".to_vec());
+    let (token, raw_tx) = put_using_payment(&api, &metadata_path, &raw_auth_wrapper).await;
+    state.node.mine_block();
+
+    // Validate against the stored public key
+    let address = metadata_path.trim_start_matches("/keys/");
+    let metadata_digest = hex::encode(digest(&SHA256, b"metadata"));
+    let validate_request = json!({
+        "address": address,
+        "metadataDigest": metadata_digest,
+        "token": token,
+    });
+    let response = request()
+        .method("POST")
+        .path("/tokens/validate")
+        .body(serde_json::to_vec(&validate_request).unwrap())
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), 200);
+    let introspection: Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(introspection["valid"], true);
+    assert_eq!(
+        introspection["outpoint"]["txId"],
+        hex::encode(tx_id(&raw_tx))
+    );
+    assert_eq!(introspection["transactionFound"], true);
+    assert_eq!(introspection["confirmations"], 1);
+    assert_eq!(introspection["commitmentMatches"], true);
+
+    // Validate against different metadata
+    let validate_request = json!({
+        "address": address,
+        "metadataDigest": hex::encode([0; 32]),
+        "token": token,
+    });
+    let response = request()
+        .method("POST")
+        .path("/tokens/validate")
+        .body(serde_json::to_vec(&validate_request).unwrap())
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), 200);
+    let introspection: Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(introspection["valid"], false);
+    assert_eq!(introspection["error"], "invalid token");
+    assert_eq!(introspection["commitmentMatches"], false);
+
+    // Wrapper size is required without stored metadata
+    let unknown_address = Address {
+        body: vec![2; 20],
+        ..Default::default()
+    };
+    let validate_request = json!({
+        "address": unknown_address.encode().unwrap(),
+        "publicKey": hex::encode([2; 33]),
+        "metadataDigest": metadata_digest,
+        "token": token,
+    });
+    let response = request()
+        .method("POST")
+        .path("/tokens/validate")
+        .body(serde_json::to_vec(&validate_request).unwrap())
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), 400);
+    assert_eq!(
+        response.body(),
+        "no wrapper size given or stored for the address"
+    );
+
+    drop(api);
+    state.destroy();
+}
+
+#[tokio::test]
+async fn admin_requires_api_key() {
+    let (api, state) = test_api("admin_flow");
+
+    let response = request()
+        .method("GET")
+        .path("/admin/peers")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), 401);
+
+    let response = request()
+        .method("POST")
+        .path("/admin/peers/crawl")
+        .header("Api-Key", "unknown")
+        .reply(&api)
+        .await;
+    assert_eq!(response.status(), 401);
+
+    drop(api);
+    state.destroy();
+}
+
+#[tokio::test]
+async fn info() {
+    let (api, state) = test_api("info_flow");
+
+    let response = request().method("GET").path("/info").reply(&api).await;
+    assert_eq!(response.status(), 200);
+    let info: Value = serde_json::from_slice(response.body()).unwrap();
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(info["network"], SETTINGS.network.as_str());
+    assert_eq!(info["metadataSize"], SETTINGS.limits.metadata_size);
+    assert!(info["features"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("sync")));
+
+    drop(api);
+    state.destroy();
+}